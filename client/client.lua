@@ -198,7 +198,7 @@ while true do
             result = {}
             for slot = 1, count do
               local item = stacks()
-              if item and item.name and item.size > 0 then
+              if item and item.name and (item.size > 0 or p.keepEmpty) then
                 item.aspects = nil
                 result[slot] = item
               elseif slot == count then
@@ -221,6 +221,30 @@ while true do
           me.setInterfaceConfiguration(p.entry, dbAddr, p.entry, p.size)
           inv.transferItem(table.unpack(p.args))
           me.setInterfaceConfiguration(1)
+        elseif p.op == "listMEFluid" then
+          result = {}
+          for _, fluid in ipairs(inv.getFluidsInNetwork()) do
+            if fluid and fluid.name and fluid.amount > 0 then
+              table.insert(result, {name = fluid.name, label = fluid.label, amount = fluid.amount})
+            end
+          end
+        elseif p.op == "xferMEFluid" then
+          local me = getInv(p.me)
+          db.clear(p.entry)
+          me.store(p.filter, dbAddr, p.entry, 1)
+          me.setFluidInterfaceConfiguration(p.entry, dbAddr, p.entry)
+          inv.transferFluid(table.unpack(p.args))
+          me.setFluidInterfaceConfiguration(p.entry)
+        elseif p.op == "listRS" then
+          result = {}
+          for _, item in ipairs(inv.getItems()) do
+            if item and item.name and item.size > 0 then
+              table.insert(result, item)
+            end
+          end
+        elseif p.op == "xferRS" then
+          getInv(p.rs).extractItem(p.filter, p.size, p.direction)
+          inv.transferItem(table.unpack(p.args))
         elseif p.op == "call" then
           -- transferItem (OC): fromSide, toSide, [size, [fromSlot, [toSlot]]]
           result = {inv[p.fn](table.unpack(p.args))}
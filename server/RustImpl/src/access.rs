@@ -1,4 +1,5 @@
-use flexstr::LocalStr;
+use super::action::{Call, List};
+use flexstr::{local_str, LocalStr};
 
 pub trait Access {
     fn get_client(&self) -> &str;
@@ -27,6 +28,129 @@ pub struct InvAccess {
     pub inv_side: u8,
 }
 
+impl_access!(CCAccess);
+pub struct CCAccess {
+    pub client: LocalStr,
+    // Peripheral name on the wired network, e.g. minecraft:chest_0
+    pub name: LocalStr,
+}
+
+impl_access!(CCInvAccess);
+pub struct CCInvAccess {
+    pub client: LocalStr,
+    pub inv_name: LocalStr,
+    pub bus_name: LocalStr,
+}
+
+// Moves items between an inventory and the bus, using either an OC transposer or CC:Tweaked generic peripherals.
+pub enum ItemAccess {
+    OC(InvAccess),
+    CC(CCInvAccess),
+}
+
+impl From<InvAccess> for ItemAccess {
+    fn from(x: InvAccess) -> Self { ItemAccess::OC(x) }
+}
+
+impl From<CCInvAccess> for ItemAccess {
+    fn from(x: CCInvAccess) -> Self { ItemAccess::CC(x) }
+}
+
+impl Access for ItemAccess {
+    fn get_client(&self) -> &str {
+        match self {
+            ItemAccess::OC(x) => x.get_client(),
+            ItemAccess::CC(x) => x.get_client(),
+        }
+    }
+}
+
+impl ItemAccess {
+    // The inventory's component address or peripheral name, for display.
+    pub fn get_addr(&self) -> &LocalStr {
+        match self {
+            ItemAccess::OC(x) => &x.addr,
+            ItemAccess::CC(x) => &x.inv_name,
+        }
+    }
+
+    pub fn list(&self, keep_empty: bool) -> List {
+        match self {
+            ItemAccess::OC(x) => List { addr: x.addr.clone(), side: Some(x.inv_side), keep_empty },
+            ItemAccess::CC(x) => List { addr: x.inv_name.clone(), side: None, keep_empty },
+        }
+    }
+
+    pub fn to_bus(&self, size: i64, inv_slot: usize, bus_slot: usize) -> Call {
+        match self {
+            ItemAccess::OC(x) => Call {
+                addr: x.addr.clone(),
+                func: local_str!("transferItem"),
+                args: vec![
+                    x.inv_side.into(),
+                    x.bus_side.into(),
+                    size.into(),
+                    (inv_slot + 1).into(),
+                    (bus_slot + 1).into(),
+                ],
+            },
+            ItemAccess::CC(x) => Call {
+                addr: x.inv_name.clone(),
+                func: local_str!("pushItems"),
+                args: vec![x.bus_name.clone().into(), (inv_slot + 1).into(), size.into(), (bus_slot + 1).into()],
+            },
+        }
+    }
+
+    // Without inv_slot, the inventory decides where the items go.
+    pub fn from_bus(&self, size: i64, bus_slot: usize, inv_slot: Option<usize>) -> Call {
+        match self {
+            ItemAccess::OC(x) => {
+                let mut args = vec![x.bus_side.into(), x.inv_side.into(), size.into(), (bus_slot + 1).into()];
+                args.extend(inv_slot.map(|x| (x + 1).into()));
+                Call { addr: x.addr.clone(), func: local_str!("transferItem"), args }
+            }
+            ItemAccess::CC(x) => {
+                let mut args = vec![x.bus_name.clone().into(), (bus_slot + 1).into(), size.into()];
+                args.extend(inv_slot.map(|x| (x + 1).into()));
+                Call { addr: x.inv_name.clone(), func: local_str!("pullItems"), args }
+            }
+        }
+    }
+}
+
+// An inventory that is only listed, such as the bus.
+pub enum ListAccess {
+    OC(SidedAccess),
+    CC(CCAccess),
+}
+
+impl From<SidedAccess> for ListAccess {
+    fn from(x: SidedAccess) -> Self { ListAccess::OC(x) }
+}
+
+impl From<CCAccess> for ListAccess {
+    fn from(x: CCAccess) -> Self { ListAccess::CC(x) }
+}
+
+impl Access for ListAccess {
+    fn get_client(&self) -> &str {
+        match self {
+            ListAccess::OC(x) => x.get_client(),
+            ListAccess::CC(x) => x.get_client(),
+        }
+    }
+}
+
+impl ListAccess {
+    pub fn list(&self) -> List {
+        match self {
+            ListAccess::OC(x) => List { addr: x.addr.clone(), side: Some(x.side), keep_empty: false },
+            ListAccess::CC(x) => List { addr: x.name.clone(), side: None, keep_empty: false },
+        }
+    }
+}
+
 impl_access!(MEAccess);
 pub struct MEAccess {
     pub client: LocalStr,
@@ -38,6 +162,20 @@ pub struct MEAccess {
     pub me_slot: usize,
 }
 
+impl_access!(RSAccess);
+pub struct RSAccess {
+    pub client: LocalStr,
+    pub transposer_addr: LocalStr,
+    pub rs_addr: LocalStr,
+    pub bus_side: u8,
+    // Transposer side of an RS interface or importer for deposit.
+    pub rs_side: u8,
+    // Transposer side of an empty inventory that extracted items pass through.
+    pub buffer_side: u8,
+    // Direction from the RS node to the buffer.
+    pub buffer_direction: u8,
+}
+
 impl_access!(ComponentAccess);
 pub struct ComponentAccess {
     pub client: LocalStr,
@@ -99,6 +237,16 @@ pub struct TankAccess {
     pub buses: Vec<EachBusOfTank>,
 }
 
+impl_access!(MEFluidAccess);
+pub struct MEFluidAccess {
+    pub client: LocalStr,
+    pub me_addr: LocalStr,
+    // Tank of the fluid interface that is configured for extraction.
+    pub me_slot: usize,
+    // For each fluid bus; tank_side faces the fluid interface.
+    pub buses: Vec<EachBusOfTank>,
+}
+
 impl_access!(InvTankAccess);
 pub struct InvTankAccess {
     pub client: LocalStr,
@@ -1,5 +1,5 @@
 use super::item::ItemStack;
-use super::lua_value::{table_to_vec, vec_to_table, Table, Value};
+use super::lua_value::{table_remove, table_to_vec, vec_to_table, Table, Value};
 use flexstr::LocalStr;
 use std::{
     cell::RefCell,
@@ -104,7 +104,10 @@ impl Action for Print {
 
 pub struct List {
     pub addr: LocalStr,
-    pub side: u8,
+    // None for CC peripherals, which are listed by name alone through `listCC`.
+    pub side: Option<u8>,
+    // Also report items of size 0, e.g. in locked drawers.
+    pub keep_empty: bool,
 }
 
 impl Action for List {
@@ -112,9 +115,16 @@ impl Action for List {
 
     fn build_request(self) -> Value {
         let mut result = Table::new();
-        result.insert("op".into(), "list".into());
-        result.insert("side".into(), self.side.into());
+        if let Some(side) = self.side {
+            result.insert("op".into(), "list".into());
+            result.insert("side".into(), side.into());
+        } else {
+            result.insert("op".into(), "listCC".into());
+        }
         result.insert("inv".into(), self.addr.into());
+        if self.keep_empty {
+            result.insert("keepEmpty".into(), true.into());
+        }
         result.into()
     }
 
@@ -153,7 +163,7 @@ pub struct XferME {
     pub me_addr: LocalStr,
     pub me_slot: usize,
     pub filter: Value,
-    pub size: i32,
+    pub size: i64,
     pub transposer_addr: LocalStr,
     pub transposer_args: Vec<Value>,
 }
@@ -176,6 +186,112 @@ impl Action for XferME {
     fn parse_response(_: Value) -> Result<(), LocalStr> { Ok(()) }
 }
 
+pub struct StoredFluid {
+    pub name: LocalStr,
+    pub label: LocalStr,
+    pub qty: i64,
+}
+
+pub struct ListMEFluid {
+    pub addr: LocalStr,
+}
+
+impl Action for ListMEFluid {
+    type Output = Vec<StoredFluid>;
+
+    fn build_request(self) -> Value {
+        let mut result = Table::new();
+        result.insert("op".into(), "listMEFluid".into());
+        result.insert("inv".into(), self.addr.into());
+        result.into()
+    }
+
+    fn parse_response(response: Value) -> Result<Vec<StoredFluid>, LocalStr> {
+        table_to_vec(response.try_into()?)?
+            .into_iter()
+            .map(|x| {
+                let mut x: Table = x.try_into()?;
+                Ok(StoredFluid {
+                    name: table_remove(&mut x, "name")?,
+                    label: table_remove(&mut x, "label")?,
+                    qty: table_remove(&mut x, "amount")?,
+                })
+            })
+            .collect()
+    }
+}
+
+pub struct XferMEFluid {
+    pub me_addr: LocalStr,
+    pub me_slot: usize,
+    pub filter: Value,
+    pub transposer_addr: LocalStr,
+    pub transposer_args: Vec<Value>,
+}
+
+impl Action for XferMEFluid {
+    type Output = ();
+
+    fn build_request(self) -> Value {
+        let mut result = Table::new();
+        result.insert("op".into(), "xferMEFluid".into());
+        result.insert("me".into(), self.me_addr.into());
+        result.insert("entry".into(), (self.me_slot + 1).into());
+        result.insert("filter".into(), self.filter);
+        result.insert("inv".into(), self.transposer_addr.into());
+        result.insert("args".into(), vec_to_table(self.transposer_args).into());
+        result.into()
+    }
+
+    fn parse_response(_: Value) -> Result<(), LocalStr> { Ok(()) }
+}
+
+pub struct ListRS {
+    pub addr: LocalStr,
+}
+
+impl Action for ListRS {
+    type Output = Vec<ItemStack>;
+
+    fn build_request(self) -> Value {
+        let mut result = Table::new();
+        result.insert("op".into(), "listRS".into());
+        result.insert("inv".into(), self.addr.into());
+        result.into()
+    }
+
+    fn parse_response(response: Value) -> Result<Vec<ItemStack>, LocalStr> {
+        table_to_vec(response.try_into()?)?.into_iter().map(ItemStack::parse).collect()
+    }
+}
+
+pub struct XferRS {
+    pub rs_addr: LocalStr,
+    pub filter: Value,
+    pub size: i64,
+    pub direction: u8,
+    pub transposer_addr: LocalStr,
+    pub transposer_args: Vec<Value>,
+}
+
+impl Action for XferRS {
+    type Output = ();
+
+    fn build_request(self) -> Value {
+        let mut result = Table::new();
+        result.insert("op".into(), "xferRS".into());
+        result.insert("rs".into(), self.rs_addr.into());
+        result.insert("filter".into(), self.filter);
+        result.insert("size".into(), self.size.into());
+        result.insert("direction".into(), self.direction.into());
+        result.insert("inv".into(), self.transposer_addr.into());
+        result.insert("args".into(), vec_to_table(self.transposer_args).into());
+        result.into()
+    }
+
+    fn parse_response(_: Value) -> Result<(), LocalStr> { Ok(()) }
+}
+
 pub struct Call {
     pub addr: LocalStr,
     pub func: LocalStr,
@@ -0,0 +1,68 @@
+// Run with `cargo +nightly bench --features bench`.
+extern crate test;
+use crate::config_util::expr;
+use crate::item::{Filter, FilterCache, Item};
+use crate::lua_value::Table;
+use fnv::FnvHashMap;
+use std::{hint::black_box, rc::Rc};
+use test::Bencher;
+
+const N_ITEMS: usize = 20000;
+const N_FILTERS: usize = 20;
+// How many times each filter is searched per cycle, e.g. by recipes, outputs and alerts.
+const N_SEARCHES: usize = 20;
+
+fn make_items() -> FnvHashMap<Rc<Item>, i64> {
+    FnvHashMap::from_iter((0..N_ITEMS).map(|i| {
+        let item = Item {
+            label: format!("Item {}", i).into(),
+            name: format!("mod{}:item{}", i % 100, i).into(),
+            damage: (i % 16) as _,
+            max_damage: 16,
+            max_size: 64,
+            has_tag: i % 7 == 0,
+            others: Table::new(),
+        };
+        (Rc::new(item), i as _)
+    }))
+}
+
+fn make_filters() -> Vec<Filter> {
+    Vec::from_iter((0..N_FILTERS).map(|i| expr(format!("name ~ ^mod{}: & damage > 3", i).leak())))
+}
+
+fn run_cycle(items: &FnvHashMap<Rc<Item>, i64>, filters: &[Filter], cache: Option<&FilterCache>) -> i64 {
+    let mut sum = 0;
+    for _ in 0..N_SEARCHES {
+        for filter in filters {
+            if let Some(cache) = cache {
+                cache.for_each_match(filter, items, |_, n| sum += n)
+            } else {
+                for (item, n) in items {
+                    if filter.apply(item) {
+                        sum += n
+                    }
+                }
+            }
+        }
+    }
+    sum
+}
+
+#[bench]
+fn search_uncached(b: &mut Bencher) {
+    let (items, filters) = (make_items(), make_filters());
+    b.iter(|| black_box(run_cycle(&items, &filters, None)))
+}
+
+#[bench]
+fn search_cached(b: &mut Bencher) {
+    let (items, filters) = (make_items(), make_filters());
+    assert_eq!(run_cycle(&items, &filters, None), run_cycle(&items, &filters, Some(&FilterCache::default())));
+    b.iter(|| {
+        let mut cache = FilterCache::default();
+        let result = run_cycle(&items, &filters, Some(&cache));
+        cache.clear();
+        black_box(result)
+    })
+}
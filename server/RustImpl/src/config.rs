@@ -1,4 +1,5 @@
 use crate::factory::{Factory, FactoryConfig};
+use crate::item::IdentityPolicy;
 use crate::{access::*, config_util::*, process::*, recipe::*, side::*, storage::*};
 use crate::{server::Server, Tui};
 use std::{cell::RefCell, rc::Rc, time::Duration};
@@ -9,74 +10,97 @@ pub fn build_factory(tui: Rc<Tui>) -> Rc<RefCell<Factory>> {
         server: Server::new(tui, 1847),
         min_cycle_time: Duration::from_secs(1),
         log_clients: vec![s("main")],
-        bus_accesses: vec![SidedAccess { client: s("1a"), addr: s("538"), side: EAST }],
+        bus_accesses: vec![SidedAccess { client: s("1a"), addr: s("538"), side: EAST }.into()],
         fluid_bus_accesses: vec![],
         fluid_bus_capacity: 0,
         backups: vec![(label("Potato"), 32)],
         fluid_backups: vec![],
+        identity: IdentityPolicy::default(),
+        identity_overrides: vec![],
+        overrides_file: Some(s("overrides.txt")),
+        budgets: vec![],
+        seed_backup: 1,
+        overflows: vec![],
+        min_free_slots: 0,
+        defrag_moves: 0,
+        defrag_interval: Duration::from_secs(60),
     }
     .build(|factory| {
         factory.add_process(ManualUiConfig { accesses: vec![] });
         factory.add_storage(ChestConfig {
-            accesses: vec![InvAccess { client: s("1a"), addr: s("538"), bus_side: EAST, inv_side: UP }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("538"), bus_side: EAST, inv_side: UP }.into()],
+            deposit: DepositRule::new(0),
+            extract_priority: 0,
+            extract_strategy: ExtractStrategy::SmallestFirst,
         });
         factory.add_process(BufferedConfig {
             name: s("output"),
-            accesses: vec![InvAccess { client: s("1a"), addr: s("677"), bus_side: EAST, inv_side: UP }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("677"), bus_side: EAST, inv_side: UP }.into()],
             slot_filter: None,
             to_extract: extract_all(),
             recipes: vec![],
+            demand_policy: by_priority(),
             max_recipe_inputs: 0,
             stocks: vec![],
         });
         factory.add_process(BufferedConfig {
             name: s("stock"),
-            accesses: vec![InvAccess { client: s("1a"), addr: s("c65"), bus_side: WEST, inv_side: UP }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("c65"), bus_side: WEST, inv_side: UP }.into()],
             slot_filter: None,
             to_extract: None,
             recipes: vec![],
+            demand_policy: by_priority(),
             max_recipe_inputs: 0,
             stocks: vec![BufferedInput::new(label("Bio Fuel"), 64), BufferedInput::new(label("Fluxed Phyto-Gro"), 64)],
         });
         factory.add_process(BlockingOutputConfig {
-            accesses: vec![InvAccess { client: s("1a"), addr: s("f59"), bus_side: EAST, inv_side: UP }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("f59"), bus_side: EAST, inv_side: UP }.into()],
             slot_filter: None,
-            outputs: vec![Output { item: label("Cobblestone"), n_wanted: 64 }],
+            outputs: vec![Output { item: label("Cobblestone"), n_wanted: 64, aggregate: false }],
         });
         factory.add_process(SlottedConfig {
             name: s("manufactory"),
-            accesses: vec![InvAccess { client: s("1a"), addr: s("2e2"), bus_side: WEST, inv_side: UP }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("2e2"), bus_side: WEST, inv_side: UP }.into()],
             input_slots: vec![0],
             to_extract: None,
             strict_priority: false,
+            demand_policy: by_priority(),
             recipes: vec![
                 SlottedRecipe {
                     outputs: Output::new(label("Sand"), 64),
                     inputs: vec![SlottedInput::new(label("Cobblestone"), vec![(0, 1)])],
                     max_sets: 8,
+                    min_sets: 1,
+                    max_wait: None,
                 },
                 SlottedRecipe {
                     outputs: Output::new(label("Niter"), 64),
                     inputs: vec![SlottedInput::new(label("Sandstone"), vec![(0, 1)])],
                     max_sets: 8,
+                    min_sets: 1,
+                    max_wait: None,
                 },
                 SlottedRecipe {
                     outputs: Output::new(label("Pulverized Charcoal"), 64),
                     inputs: vec![SlottedInput::new(label("Charcoal"), vec![(0, 1)])],
                     max_sets: 8,
+                    min_sets: 1,
+                    max_wait: None,
                 },
             ],
         });
         factory.add_process(BufferedConfig {
             name: s("crafter"),
-            accesses: vec![InvAccess { client: s("1a"), addr: s("0c7"), bus_side: EAST, inv_side: UP }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("0c7"), bus_side: EAST, inv_side: UP }.into()],
             slot_filter: None,
             to_extract: None,
             recipes: vec![
                 BufferedRecipe {
                     outputs: Output::new(label("Sandstone"), 64),
                     inputs: vec![BufferedInput::new(label("Sand"), 4)],
-                    max_inputs: i32::MAX,
+                    max_inputs: i64::MAX,
+                    min_sets: 1,
+                    max_wait: None,
                 },
                 BufferedRecipe {
                     outputs: Output::new(label("Rich Phyto-Gro"), 64),
@@ -85,86 +109,106 @@ pub fn build_factory(tui: Rc<Tui>) -> Rc<RefCell<Factory>> {
                         BufferedInput::new(label("Niter"), 1),
                         BufferedInput::new(label("Rich Slag"), 1),
                     ],
-                    max_inputs: i32::MAX,
+                    max_inputs: i64::MAX,
+                    min_sets: 1,
+                    max_wait: None,
                 },
                 BufferedRecipe {
                     outputs: Output::new(label("Compass"), 64),
                     inputs: vec![BufferedInput::new(label("Iron Ingot"), 4), BufferedInput::new(label("Redstone"), 1)],
-                    max_inputs: i32::MAX,
+                    max_inputs: i64::MAX,
+                    min_sets: 1,
+                    max_wait: None,
                 },
                 BufferedRecipe {
                     outputs: Output::new(label("Redstone"), 64),
                     inputs: vec![BufferedInput::new(label("Redstone Essence"), 9)],
-                    max_inputs: i32::MAX,
+                    max_inputs: i64::MAX,
+                    min_sets: 1,
+                    max_wait: None,
                 },
             ],
-            max_recipe_inputs: i32::MAX,
+            demand_policy: by_priority(),
+            max_recipe_inputs: i64::MAX,
             stocks: vec![],
         });
         factory.add_process(SlottedConfig {
             name: s("charger"),
-            accesses: vec![InvAccess { client: s("1a"), addr: s("007"), bus_side: WEST, inv_side: UP }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("007"), bus_side: WEST, inv_side: UP }.into()],
             input_slots: vec![0],
             to_extract: None,
             strict_priority: false,
+            demand_policy: by_priority(),
             recipes: vec![SlottedRecipe {
                 outputs: Output::new(label("Fluxed Phyto-Gro"), 64),
                 inputs: vec![SlottedInput::new(label("Rich Phyto-Gro"), vec![(0, 1)])],
-                max_sets: i32::MAX,
+                max_sets: i64::MAX,
+                min_sets: 1,
+                max_wait: None,
             }],
         });
         factory.add_process(ScatteringConfig {
             name: s("crusher"),
-            accesses: vec![InvAccess { client: s("1a"), addr: s("525"), bus_side: EAST, inv_side: UP }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("525"), bus_side: EAST, inv_side: UP }.into()],
             input_slots: vec![0, 1, 2, 3, 4, 5, 6],
             to_extract: None,
             recipes: vec![ScatteringRecipe::new(
                 Output::new(label("Bio Fuel"), 64),
                 ScatteringInput::new(label("Potato")),
             )],
+            demand_policy: by_priority(),
             max_per_slot: 4,
         });
         factory.add_process(ScatteringConfig {
             name: s("furnace"),
-            accesses: vec![InvAccess { client: s("1a"), addr: s("346"), bus_side: WEST, inv_side: UP }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("346"), bus_side: WEST, inv_side: UP }.into()],
             input_slots: vec![0, 1, 2, 3, 4, 5, 6],
             to_extract: None,
             recipes: vec![ScatteringRecipe::new(
                 Output::new(label("Charcoal"), 64),
                 ScatteringInput::new(label("Birch Wood")),
             )],
+            demand_policy: by_priority(),
             max_per_slot: 4,
         });
         factory.add_process(SlottedConfig {
             name: s("phyto"),
-            accesses: vec![InvAccess { client: s("1a"), addr: s("693"), bus_side: WEST, inv_side: UP }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("693"), bus_side: WEST, inv_side: UP }.into()],
             input_slots: vec![0],
             to_extract: None,
             strict_priority: false,
+            demand_policy: by_priority(),
             recipes: vec![
                 SlottedRecipe {
                     outputs: Output::new(label("Potato"), 64),
                     inputs: vec![SlottedInput::new(label("Potato"), vec![(0, 1)]).allow_backup()],
                     max_sets: 4,
+                    min_sets: 1,
+                    max_wait: None,
                 },
                 SlottedRecipe {
                     outputs: Output::new(label("Redstone Essence"), 64),
                     inputs: vec![SlottedInput::new(label("Redstone Seeds"), vec![(0, 1)])],
                     max_sets: 4,
+                    min_sets: 1,
+                    max_wait: None,
                 },
                 SlottedRecipe {
                     outputs: Output::new(label("Birch Wood"), 64),
                     inputs: vec![SlottedInput::new(label("Birch Sapling"), vec![(0, 1)])],
                     max_sets: 4,
+                    min_sets: 1,
+                    max_wait: None,
                 },
             ],
         });
         factory.add_process(SlottedConfig {
             name: s("induction"),
-            accesses: vec![InvAccess { client: s("1a"), addr: s("0f5"), bus_side: EAST, inv_side: UP }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("0f5"), bus_side: EAST, inv_side: UP }.into()],
             input_slots: vec![0, 1],
             to_extract: None,
             strict_priority: false,
+            demand_policy: by_priority(),
             recipes: vec![SlottedRecipe {
                 outputs: Output::new(label("Rich Slag"), 64),
                 inputs: vec![
@@ -172,31 +216,40 @@ pub fn build_factory(tui: Rc<Tui>) -> Rc<RefCell<Factory>> {
                     SlottedInput::new(label("Compass"), vec![(1, 1)]),
                 ],
                 max_sets: 8,
+                min_sets: 1,
+                max_wait: None,
             }],
         });
         factory.add_process(BufferedConfig {
             name: s("trash"),
-            accesses: vec![InvAccess { client: s("1a"), addr: s("c65"), bus_side: WEST, inv_side: NORTH }],
+            accesses: vec![InvAccess { client: s("1a"), addr: s("c65"), bus_side: WEST, inv_side: NORTH }.into()],
             slot_filter: None,
             to_extract: None,
             recipes: vec![
                 BufferedRecipe {
                     outputs: ignore_outputs(0.),
                     inputs: vec![BufferedInput::new(label("Poisonous Potato"), 1).extra_backup(64)],
-                    max_inputs: i32::MAX,
+                    max_inputs: i64::MAX,
+                    min_sets: 1,
+                    max_wait: None,
                 },
                 BufferedRecipe {
                     outputs: ignore_outputs(0.),
                     inputs: vec![BufferedInput::new(label("Redstone Seeds"), 1).extra_backup(64)],
-                    max_inputs: i32::MAX,
+                    max_inputs: i64::MAX,
+                    min_sets: 1,
+                    max_wait: None,
                 },
                 BufferedRecipe {
                     outputs: ignore_outputs(0.),
                     inputs: vec![BufferedInput::new(label("Birch Sapling"), 1).extra_backup(64)],
-                    max_inputs: i32::MAX,
+                    max_inputs: i64::MAX,
+                    min_sets: 1,
+                    max_wait: None,
                 },
             ],
-            max_recipe_inputs: i32::MAX,
+            demand_policy: by_priority(),
+            max_recipe_inputs: i64::MAX,
             stocks: vec![],
         })
     })
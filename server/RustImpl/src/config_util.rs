@@ -7,6 +7,10 @@ pub fn label(x: &'static str) -> Filter { Filter::Label(s(x)) }
 pub fn name(x: &'static str) -> Filter { Filter::Name(s(x)) }
 pub fn both(label: &'static str, name: &'static str) -> Filter { Filter::Both { label: s(label), name: s(name) } }
 
+pub fn expr(text: &'static str) -> Filter {
+    Filter::Expr(Rc::new(text.parse().unwrap_or_else(|e| panic!("invalid filter {:?}: {}", text, e))))
+}
+
 pub fn custom(desc: &'static str, func: impl Fn(&Item) -> bool + 'static) -> Filter {
     Filter::Custom { desc: s(desc), func: Rc::new(func) }
 }
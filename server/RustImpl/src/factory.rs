@@ -1,10 +1,15 @@
-use crate::access::{Access, EachTank, FluidAccess, SidedAccess, TankAccess};
-use crate::action::{ActionFuture, Call, List, Print};
-use crate::item::{Filter, Item, ItemStack};
+use crate::access::{Access, EachTank, FluidAccess, ItemAccess, ListAccess, TankAccess};
+use crate::action::{ActionFuture, Call, Print};
+use crate::item::{Filter, FilterCache, FilterExpr, FilterKey, IdentityPolicy, Item, ItemStack};
 use crate::lua_value::{call_result, table_remove, table_to_vec, Table};
+use crate::overrides::{OverrideKind, Overrides};
 use crate::process::{IntoProcess, Process};
+use crate::recipe::{Outputs, Proposal, RecipeId};
 use crate::server::Server;
-use crate::storage::{DepositResult, Extractor, IntoStorage, Provider, Storage};
+use crate::storage::{
+    DepositResult, Extractor, FluidExtractor, IntoStorage, MEFluidConfig, MEFluidStorage, PartialStack, Provider,
+    Storage, StorageUsage,
+};
 use crate::util::{alive, join_outputs, join_tasks, make_local_one_shot, spawn, LocalReceiver, LocalSender};
 use crate::Tui;
 use abort_on_drop::ChildTask;
@@ -13,17 +18,19 @@ use fnv::{FnvHashMap, FnvHashSet};
 use std::{
     cell::{Ref, RefCell},
     cmp::{max, min},
-    collections::{hash_map::Entry, BinaryHeap, VecDeque},
+    collections::{hash_map::Entry, BTreeMap, BinaryHeap, VecDeque},
+    fmt::Display,
     future::Future,
-    mem::take,
+    iter::once,
+    mem::{swap, take},
     rc::{Rc, Weak},
     time::Duration,
 };
 use tokio::time::{sleep_until, Instant};
 
 pub struct ItemInfo {
-    pub n_stored: i32,
-    n_backup: i32,
+    pub n_stored: i64,
+    n_backup: i64,
     providers: BinaryHeap<Provider>,
 }
 
@@ -36,7 +43,17 @@ impl ItemInfo {
         }
     }
 
-    pub fn get_availability(&self, allow_backup: bool, extra_backup: i32) -> i32 {
+    // Reserves a whole provider if nothing was reserved from it yet, e.g. to move a stack elsewhere.
+    pub fn reserve_provider(&mut self, extractor: &Rc<dyn Extractor>, size: i64) -> bool {
+        let found = self.providers.iter().any(|x| Rc::ptr_eq(&x.extractor, extractor) && x.n_provided.get() == size);
+        if found {
+            self.providers.retain(|x| !Rc::ptr_eq(&x.extractor, extractor));
+            self.n_stored -= size
+        }
+        found
+    }
+
+    pub fn get_availability(&self, allow_backup: bool, extra_backup: i64) -> i64 {
         let mut result = self.n_stored - extra_backup;
         if !allow_backup {
             result -= self.n_backup;
@@ -44,7 +61,7 @@ impl ItemInfo {
         max(0, result)
     }
 
-    fn reserve(&mut self, mut size: i32) -> Reservation {
+    fn reserve(&mut self, mut size: i64) -> Reservation {
         let mut extractors = Vec::new();
         while size > 0 {
             let best = self.providers.peek().unwrap();
@@ -65,7 +82,7 @@ impl ItemInfo {
 }
 
 pub struct Reservation {
-    extractors: Vec<(Rc<dyn Extractor>, i32)>,
+    extractors: Vec<(Rc<dyn Extractor>, i64)>,
 }
 
 impl Reservation {
@@ -77,37 +94,46 @@ impl Reservation {
 }
 
 pub struct FluidReservation {
-    extractors: Vec<(Weak<RefCell<FluidStorage>>, i64)>,
+    extractors: Vec<(Rc<dyn FluidExtractor>, i64)>,
 }
 
 impl FluidReservation {
     pub fn extract(self, bus: usize) -> impl Future<Output = Result<(), LocalStr>> {
-        join_tasks(Vec::from_iter(self.extractors.into_iter().map(|(storage, qty)| {
-            spawn(async move {
-                let task;
-                {
-                    alive!(storage, storage);
-                    upgrade!(storage.factory, factory);
-                    let server = factory.config.server.borrow();
-                    let access = server.load_balance(&storage.config.accesses).1;
-                    let bus_of_tank = &access.buses[bus];
-                    task = ActionFuture::from(Call {
-                        addr: bus_of_tank.addr.clone(),
-                        func: local_str!("transferFluid"),
-                        args: vec![
-                            bus_of_tank.tank_side.into(),
-                            bus_of_tank.bus_side.into(),
-                            qty.into(),
-                            (storage.slot.unwrap() + 1).into(),
-                        ],
-                    });
-                    server.enqueue_request_group(&access.client, vec![task.clone().into()])
-                }
-                task.await?;
-                alive_mut!(storage, storage);
-                Ok(storage.n_stored_hi -= qty)
-            })
-        })))
+        join_tasks(Vec::from_iter(self.extractors.into_iter().map(|(extractor, qty)| extractor.extract(qty, bus))))
+    }
+}
+
+struct TankExtractor {
+    weak: Weak<RefCell<FluidStorage>>,
+}
+
+impl FluidExtractor for TankExtractor {
+    fn extract(&self, qty: i64, bus: usize) -> ChildTask<Result<(), LocalStr>> {
+        let storage = self.weak.clone();
+        spawn(async move {
+            let task;
+            {
+                alive!(storage, storage);
+                upgrade!(storage.factory, factory);
+                let server = factory.config.server.borrow();
+                let access = server.load_balance(&storage.config.accesses).1;
+                let bus_of_tank = &access.buses[bus];
+                task = ActionFuture::from(Call {
+                    addr: bus_of_tank.addr.clone(),
+                    func: local_str!("transferFluid"),
+                    args: vec![
+                        bus_of_tank.tank_side.into(),
+                        bus_of_tank.bus_side.into(),
+                        qty.into(),
+                        (storage.slot.unwrap() + 1).into(),
+                    ],
+                });
+                server.enqueue_request_group(&access.client, vec![task.clone().into()])
+            }
+            task.await?;
+            alive_mut!(storage, storage);
+            Ok(storage.n_stored_hi -= qty)
+        })
     }
 }
 
@@ -116,22 +142,65 @@ pub struct FactoryConfig {
     pub server: Rc<RefCell<Server>>,
     pub min_cycle_time: Duration,
     pub log_clients: Vec<LocalStr>,
-    pub bus_accesses: Vec<SidedAccess>,
+    pub bus_accesses: Vec<ListAccess>,
     pub fluid_bus_accesses: Vec<FluidAccess>,
     pub fluid_bus_capacity: i64,
-    pub backups: Vec<(Filter, i32)>,
+    pub backups: Vec<(Filter, i64)>,
     pub fluid_backups: Vec<(LocalStr, i64)>,
+    pub identity: IdentityPolicy,
+    // The first matching filter decides the policy instead of `identity`.
+    pub identity_overrides: Vec<(Filter, IdentityPolicy)>,
+    // Where overrides set from the TUI are kept across restarts.
+    pub overrides_file: Option<LocalStr>,
+    pub budgets: Vec<Budget>,
+    // Backup kept for items that recipes can make more of from themselves, unless configured in `backups`.
+    pub seed_backup: i64,
+    // What to do with items no storage takes; the first matching filter decides, otherwise they're kept.
+    pub overflows: Vec<(Filter, Overflow)>,
+    // Alerts when the storages that report usage have fewer free slots in total.
+    pub min_free_slots: usize,
+    // Partial chest stacks moved per defrag run to merge them, using bus slots nobody waits for; 0 disables.
+    pub defrag_moves: usize,
+    pub defrag_interval: Duration,
+}
+
+pub enum Overflow {
+    // Moves the items into one of these inventories, e.g. a trash can or an overflow chest.
+    Dump(Vec<ItemAccess>),
+    // Leaves the items in the bus and alerts.
+    Keep,
+}
+
+// Sets executed and items extracted for a recipe, for learning its yield.
+#[derive(Default)]
+pub struct Yield {
+    pub n_sets: i64,
+    // By output filter; extractions matching several recipes are split between them.
+    pub n_produced: Vec<(Filter, f64)>,
+}
+
+// Sets to see before a learned yield is trusted.
+const MIN_YIELD_SETS: i64 = 16;
+
+// Limits how many of the matching items may be consumed in any `window`.
+pub struct Budget {
+    pub item: Filter,
+    pub amount: i64,
+    pub window: Duration,
 }
 
 pub struct FluidStorageConfig {
     pub accesses: Vec<TankAccess>,
-    pub fluid: LocalStr,
+    // None for tanks that take any fluid, one at a time, and are reassigned when empty.
+    pub fluid: Option<LocalStr>,
 }
 
 struct FluidStorage {
     weak: Weak<RefCell<FluidStorage>>,
     factory: Weak<RefCell<Factory>>,
     config: FluidStorageConfig,
+    // What the tanks hold or were assigned.
+    fluid: Option<LocalStr>,
     slot: Option<usize>,
     capacity: i64,
     n_stored_hi: i64,
@@ -145,17 +214,43 @@ pub struct Factory {
     storages: Vec<Rc<RefCell<dyn Storage>>>,
     processes: Vec<Rc<RefCell<dyn Process>>>,
     fluid_storages: Vec<Rc<RefCell<FluidStorage>>>,
+    me_fluid_storages: Vec<Rc<RefCell<MEFluidStorage>>>,
 
     pub items: FnvHashMap<Rc<Item>, RefCell<ItemInfo>>,
     label_map: FnvHashMap<LocalStr, Vec<Rc<Item>>>,
     name_map: FnvHashMap<LocalStr, Vec<Rc<Item>>>,
+    filter_cache: FilterCache,
+    // Canonical items listed this cycle, so that equal items share one allocation.
+    interned: RefCell<FnvHashSet<Rc<Item>>>,
     fluid_backups: FnvHashMap<LocalStr, i64>,
+    seeds: Vec<Rc<Item>>,
+    // By process name and recipe index.
+    yields: BTreeMap<LocalStr, BTreeMap<usize, Yield>>,
+    // As of the last storage update, by storage.
+    storage_usages: Vec<(LocalStr, Option<StorageUsage>)>,
+    pub overrides: Overrides,
+    // Items each recipe may take this cycle, for items that were contested.
+    grants: FnvHashMap<(*const (), Rc<Item>), i64>,
+    // When each recipe below its `min_sets` started waiting.
+    pub batch_waits: RefCell<FnvHashMap<*const (), Instant>>,
+    // Recipes found waiting this cycle; the others' waits are dropped at the end of it.
+    pub batch_seen: RefCell<FnvHashSet<*const ()>>,
+    // Outputs expected from machines; reported during a cycle for use in the next.
+    in_flight: FnvHashMap<FilterKey, i64>,
+    in_flight_next: FnvHashMap<FilterKey, i64>,
+    // (time, qty) consumed under each budget, oldest first.
+    budget_usages: Vec<RefCell<VecDeque<(Instant, i64)>>>,
 
     bus_task: Option<ChildTask<Result<(), LocalStr>>>,
     bus_allocations: FnvHashSet<usize>,
     bus_wait_queue: VecDeque<LocalSender<usize>>,
+    // Served only with slots left over from `bus_wait_queue`, and dropped when the bus goes idle without any.
+    bus_idle_wait_queue: VecDeque<LocalSender<usize>>,
     bus_free_queue: Vec<usize>,
+    // Items alerted as not fitting in storage, until they leave the bus.
+    full_alerted: RefCell<FnvHashSet<Rc<Item>>>,
     n_bus_updates: usize,
+    next_defrag: Instant,
 
     fluid_bus_task: Option<ChildTask<Result<(), LocalStr>>>,
     fluid_bus_allocations: FnvHashSet<usize>,
@@ -170,6 +265,11 @@ impl FactoryConfig {
         for (fluid, qty) in &self.fluid_backups {
             *fluid_backups.entry(fluid.clone()).or_default() += qty
         }
+        let budget_usages = Vec::from_iter(self.budgets.iter().map(|_| RefCell::default()));
+        let (overrides, errors) = Overrides::load(self.overrides_file.clone());
+        for e in errors {
+            self.tui.log(format!("overrides: {}", e), 0xFF0000)
+        }
         Rc::new_cyclic(|weak| {
             let mut factory = Factory {
                 weak: weak.clone(),
@@ -178,17 +278,33 @@ impl FactoryConfig {
                 storages: Vec::new(),
                 processes: Vec::new(),
                 fluid_storages: Vec::new(),
+                me_fluid_storages: Vec::new(),
 
                 items: FnvHashMap::default(),
                 label_map: FnvHashMap::default(),
                 name_map: FnvHashMap::default(),
+                filter_cache: FilterCache::default(),
+                interned: RefCell::default(),
                 fluid_backups,
+                seeds: Vec::new(),
+                yields: BTreeMap::new(),
+                storage_usages: Vec::new(),
+                overrides,
+                grants: FnvHashMap::default(),
+                batch_waits: RefCell::default(),
+                batch_seen: RefCell::default(),
+                in_flight: FnvHashMap::default(),
+                in_flight_next: FnvHashMap::default(),
+                budget_usages,
 
                 bus_task: None,
                 bus_allocations: FnvHashSet::default(),
                 bus_wait_queue: VecDeque::new(),
+                bus_idle_wait_queue: VecDeque::new(),
                 bus_free_queue: Vec::new(),
+                full_alerted: RefCell::default(),
                 n_bus_updates: 0,
+                next_defrag: Instant::now(),
 
                 fluid_bus_task: None,
                 fluid_bus_allocations: FnvHashSet::default(),
@@ -205,13 +321,14 @@ impl FactoryConfig {
 impl Factory {
     pub fn add_storage(&mut self, storage: impl IntoStorage) { self.storages.push(storage.into_storage(&self)) }
     pub fn add_process(&mut self, process: impl IntoProcess) { self.processes.push(process.into_process(&self)) }
-    pub fn get_n_stored(&self, item: &Rc<Item>) -> i32 { self.items.get(item).map_or(0, |info| info.borrow().n_stored) }
+    pub fn get_n_stored(&self, item: &Rc<Item>) -> i64 { self.items.get(item).map_or(0, |info| info.borrow().n_stored) }
     pub fn borrow_server(&self) -> Ref<Server> { self.config.server.borrow() }
     pub fn add_fluid_storage(&mut self, config: FluidStorageConfig) {
         self.fluid_storages.push(Rc::new_cyclic(|weak| {
             RefCell::new(FluidStorage {
                 weak: weak.clone(),
                 factory: self.weak.clone(),
+                fluid: config.fluid.clone(),
                 config,
                 slot: None,
                 capacity: 0,
@@ -229,40 +346,296 @@ impl Factory {
         }
     }
 
+    pub fn get_override(&self, kind: OverrideKind, key: impl Display) -> Option<i64> {
+        if self.overrides.is_empty() {
+            None
+        } else {
+            self.overrides.get(kind, &key.to_string())
+        }
+    }
+
+    fn handle_command(&mut self, command: &str) {
+        if command == "storages" {
+            let (mut n_used, mut n_total) = (0, 0);
+            for (name, usage) in &self.storage_usages {
+                let text = if let Some(usage) = usage {
+                    n_used += usage.n_used;
+                    n_total += usage.n_total;
+                    format!("{}: {}/{} slots used", name, usage.n_used, usage.n_total)
+                } else {
+                    format!("{}: capacity unknown", name)
+                };
+                self.config.tui.log(text, 0x55ABEC)
+            }
+            self.config.tui.log(format!("total: {}/{} slots used", n_used, n_total), 0x55ABEC);
+            return;
+        }
+        if command == "yields" {
+            for (process, stats) in self.yields.iter().flat_map(|(x, ys)| ys.iter().map(move |y| (x, y))) {
+                let (i_recipe, Yield { n_sets, n_produced }) = stats;
+                if n_produced.is_empty() {
+                    self.config.tui.log(format!("{} #{}: {} sets", process, i_recipe, n_sets), 0x55ABEC)
+                }
+                for (filter, n) in n_produced {
+                    let ratio = n / (*n_sets).max(1) as f64;
+                    let text = format!("{} #{} {}: {:.0}/{} = {:.3}", process, i_recipe, filter, n, n_sets, ratio);
+                    self.config.tui.log(text, 0x55ABEC)
+                }
+            }
+            if self.yields.is_empty() {
+                self.config.tui.log("no yields".to_owned(), 0x55ABEC)
+            }
+            return;
+        }
+        if command == "budgets" {
+            for (i_budget, budget) in self.config.budgets.iter().enumerate() {
+                let n_left = budget.amount - self.budget_used(i_budget);
+                let text = format!("{}: {}/{} left per {:?}", budget.item, n_left, budget.amount, budget.window);
+                self.config.tui.log(text, 0x55ABEC)
+            }
+            if self.config.budgets.is_empty() {
+                self.config.tui.log("no budgets".to_owned(), 0x55ABEC)
+            }
+            return;
+        }
+        if command == "overrides" {
+            for (kind, key, qty) in self.overrides.iter() {
+                self.config.tui.log(format!("{} {} = {}", kind.name(), key, qty), 0x55ABEC)
+            }
+            if self.overrides.is_empty() {
+                self.config.tui.log("no overrides".to_owned(), 0x55ABEC)
+            }
+            return;
+        }
+        let (backups, seeds) = (&self.config.backups, &self.seeds);
+        let validate = |kind, key: &str| {
+            if kind != OverrideKind::Backup
+                || backups.iter().any(|(filter, _)| filter.to_string() == key)
+                || seeds.iter().any(|seed| *seed.label == *key)
+            {
+                return Ok(());
+            }
+            // Keys of other backups are parsed as filter expressions when applied.
+            key.parse::<FilterExpr>().map(|_| ()).map_err(|e| local_fmt!("invalid filter expression {}: {}", key, e))
+        };
+        match self.overrides.apply_command(command, validate) {
+            Ok(text) => self.config.tui.log(text.to_std_string(), 0x55ABEC),
+            Err(e) => self.config.tui.log(format!("/{}: {}", command, e), 0xFF0000),
+        }
+    }
+
+    fn budget_used(&self, i_budget: usize) -> i64 {
+        let mut usage = self.budget_usages[i_budget].borrow_mut();
+        let window = self.config.budgets[i_budget].window;
+        while let Some((time, _)) = usage.front() {
+            if time.elapsed() < window {
+                break;
+            }
+            usage.pop_front();
+        }
+        usage.iter().map(|(_, qty)| qty).sum()
+    }
+
+    pub fn covering_budgets<'a>(&'a self, item: &'a Item) -> impl Iterator<Item = usize> + 'a {
+        (self.config.budgets.iter().enumerate()).filter(|(_, budget)| budget.item.apply(item)).map(|(i, _)| i)
+    }
+
+    pub fn budget_left(&self, i_budget: usize) -> i64 {
+        (self.config.budgets[i_budget].amount - self.budget_used(i_budget)).max(0)
+    }
+
+    // How many of the item may still be consumed under all budgets covering it.
+    // Items covered by the same budget share it, which callers account for with `budget_left`.
+    pub fn get_budget(&self, item: &Item) -> i64 {
+        self.covering_budgets(item).map(|i_budget| self.budget_left(i_budget)).min().unwrap_or(i64::MAX)
+    }
+
+    fn yield_mut(&mut self, (process, i_recipe): RecipeId) -> &mut Yield {
+        if !self.yields.contains_key(process) {
+            self.yields.insert(LocalStr::from_ref(process), BTreeMap::new());
+        }
+        self.yields.get_mut(process).unwrap().entry(i_recipe).or_default()
+    }
+
+    pub fn record_sets(&mut self, recipe: RecipeId, n_sets: i64) { self.yield_mut(recipe).n_sets += n_sets }
+
+    pub fn get_n_sets(&self, (process, i_recipe): RecipeId) -> i64 {
+        self.yields.get(process).and_then(|x| x.get(&i_recipe)).map_or(0, |x| x.n_sets)
+    }
+
+    pub fn record_produced(&mut self, recipe: RecipeId, filter: &Filter, n_produced: f64) {
+        let n_produced_by_filter = &mut self.yield_mut(recipe).n_produced;
+        let key = filter.key();
+        if let Some((_, n)) = n_produced_by_filter.iter_mut().find(|(x, _)| x.key() == key) {
+            *n += n_produced
+        } else {
+            n_produced_by_filter.push((filter.clone(), n_produced))
+        }
+    }
+
+    // Items of the output extracted per set executed, once enough sets have been seen.
+    pub fn get_yield(&self, (process, i_recipe): RecipeId, filter: &Filter) -> Option<f64> {
+        let stats = self.yields.get(process)?.get(&i_recipe)?;
+        if stats.n_sets >= MIN_YIELD_SETS {
+            let key = filter.key();
+            let n_produced = stats.n_produced.iter().find(|(x, _)| x.key() == key).map_or(0., |(_, n)| *n);
+            Some(n_produced / stats.n_sets as f64)
+        } else {
+            None
+        }
+    }
+
+    // Stored items on loops of recipes that yield more of them than they take, e.g. seeds of crops.
+    // Reversible loops like ingots to blocks and back multiply to 1 and don't count.
+    fn find_seeds(&self) -> Vec<Rc<Item>> {
+        // Log of the most output per input of any recipe between two items.
+        let mut gains = FnvHashMap::<(&Rc<Item>, &Rc<Item>), f64>::default();
+        for process in &self.processes {
+            for links in process.borrow().link_recipes() {
+                for (input, n_input) in &links.inputs {
+                    let froms = self.search_items(input);
+                    for (output, n_output) in &links.outputs {
+                        let gain = (n_output / *n_input as f64).ln();
+                        for (to, _) in self.search_items(output) {
+                            for &(from, _) in &froms {
+                                let best = gains.entry((from, to)).or_insert(f64::NEG_INFINITY);
+                                *best = best.max(gain)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        // Only items both consumed and produced can be on a loop.
+        let froms = FnvHashSet::from_iter(gains.keys().map(|&(from, _)| from));
+        let mut nodes = FnvHashMap::<&Rc<Item>, usize>::default();
+        for &(_, to) in gains.keys() {
+            if froms.contains(to) {
+                let i_node = nodes.len();
+                nodes.entry(to).or_insert(i_node);
+            }
+        }
+        let n_nodes = nodes.len();
+        let mut best = vec![vec![f64::NEG_INFINITY; n_nodes]; n_nodes];
+        for ((from, to), gain) in gains {
+            if let (Some(&i), Some(&j)) = (nodes.get(from), nodes.get(to)) {
+                best[i][j] = gain
+            }
+        }
+        for k in 0..n_nodes {
+            for i in 0..n_nodes {
+                for j in 0..n_nodes {
+                    best[i][j] = best[i][j].max(best[i][k] + best[k][j])
+                }
+            }
+        }
+        let mut result = Vec::from_iter(nodes.into_iter().filter(|&(_, i)| best[i][i] > 1e-9).map(|(x, _)| x.clone()));
+        result.sort_by(|x, y| x.label.cmp(&y.label));
+        result
+    }
+
+    pub fn get_grant(&self, recipe: *const (), item: &Rc<Item>) -> i64 {
+        if self.grants.is_empty() {
+            i64::MAX
+        } else {
+            self.grants.get(&(recipe, item.clone())).copied().unwrap_or(i64::MAX)
+        }
+    }
+
+    pub fn add_in_flight(&mut self, item: &Filter, n: i64) {
+        if n > 0 {
+            *self.in_flight_next.entry(item.key()).or_default() += n
+        }
+    }
+
+    // Reports outputs that reach storage within this cycle, e.g. from crafting grids, to the processes run after.
+    pub fn add_crafted(&mut self, outputs: &dyn Outputs, n_sets: i64, recipe: RecipeId) {
+        swap(&mut self.in_flight, &mut self.in_flight_next);
+        outputs.add_in_flight(self, n_sets, None, recipe);
+        swap(&mut self.in_flight, &mut self.in_flight_next)
+    }
+
+    pub fn get_in_flight(&self, item: &Filter) -> i64 {
+        if self.in_flight.is_empty() {
+            0
+        } else {
+            self.in_flight.get(&item.key()).copied().unwrap_or_default()
+        }
+    }
+
+    fn arbitrate(&mut self) {
+        let mut proposals = FnvHashMap::<Rc<Item>, Vec<Proposal>>::default();
+        for process in &self.processes {
+            for proposal in process.borrow().propose(self) {
+                proposals.entry(proposal.item.clone()).or_default().push(proposal)
+            }
+        }
+        for (item, mut proposals) in proposals {
+            let Some(info) = self.items.get(&item) else { continue };
+            let info = info.borrow();
+            proposals.sort_by(|x, y| x.priority.total_cmp(&y.priority).reverse());
+            let mut n_granted_total = 0;
+            for proposal in proposals {
+                // Each recipe may dip into backup as far as its own input allows.
+                let n_available = info.get_availability(proposal.allow_backup, proposal.extra_backup);
+                let n_granted = proposal.size.min(n_available - n_granted_total).max(0);
+                n_granted_total += n_granted;
+                if n_granted < proposal.size {
+                    self.grants.insert((proposal.recipe, item.clone()), n_granted);
+                }
+            }
+        }
+    }
+
+    // Maps an item as listed by a client to the item it's tracked as, shared by all equal items.
+    pub fn canonicalize(&self, item: &Rc<Item>) -> Rc<Item> {
+        let overridden = self.config.identity_overrides.iter().find(|(filter, _)| filter.apply(item));
+        let canonical = overridden.map_or(&self.config.identity, |(_, policy)| policy).canonicalize(item);
+        let mut interned = self.interned.borrow_mut();
+        if let Some(interned) = interned.get(canonical.as_ref().unwrap_or(item)) {
+            return interned.clone();
+        }
+        let result = canonical.map_or_else(|| item.clone(), Rc::new);
+        interned.insert(result.clone());
+        result
+    }
+
+    // Applied wherever inventories are listed, so that stacks compare equal to the factory's items.
+    pub fn canonicalize_stacks(&self, stacks: &mut [Option<ItemStack>]) {
+        for stack in stacks.iter_mut().flatten() {
+            stack.item = self.canonicalize(&stack.item)
+        }
+    }
+
     pub fn register_stored_item(&mut self, item: Rc<Item>) -> &mut ItemInfo {
+        let item = self.canonicalize(&item);
         match self.items.entry(item) {
             Entry::Occupied(x) => x.into_mut().get_mut(),
             Entry::Vacant(x) => {
                 let item = x.key();
                 self.label_map.entry(item.label.clone()).or_default().push(item.clone());
                 self.name_map.entry(item.name.clone()).or_default().push(item.clone());
+                self.filter_cache.on_new_item(item);
                 x.insert(RefCell::new(ItemInfo { n_stored: 0, n_backup: 0, providers: BinaryHeap::new() })).get_mut()
             }
         }
     }
 
-    pub fn search_item<'a>(&'a self, filter: &Filter) -> Option<(&'a Rc<Item>, &'a RefCell<ItemInfo>)> {
-        let mut best: Option<(&'a Rc<Item>, &'a RefCell<ItemInfo>)> = None;
-        let mut on_candidate = |(new_item, new_info): (&'a Rc<Item>, &'a RefCell<ItemInfo>)| {
-            if let Some((_, old_info)) = best {
-                if new_info.borrow().n_stored <= old_info.borrow().n_stored {
-                    return;
-                }
-            }
-            best = Some((new_item, new_info))
-        };
+    fn for_each_match<'a>(&'a self, filter: &Filter, mut on_match: impl FnMut(&'a Rc<Item>, &'a RefCell<ItemInfo>)) {
         match filter {
             Filter::Label(label) => {
                 if let Some(items) = self.label_map.get(&*label) {
                     for item in items {
-                        on_candidate(self.items.get_key_value(item).unwrap())
+                        let (item, info) = self.items.get_key_value(item).unwrap();
+                        on_match(item, info)
                     }
                 }
             }
             Filter::Name(name) => {
                 if let Some(items) = self.name_map.get(&*name) {
                     for item in items {
-                        on_candidate(self.items.get_key_value(item).unwrap())
+                        let (item, info) = self.items.get_key_value(item).unwrap();
+                        on_match(item, info)
                     }
                 }
             }
@@ -270,26 +643,47 @@ impl Factory {
                 if let Some(items) = self.label_map.get(&*label) {
                     for item in items {
                         if item.name == *name {
-                            on_candidate(self.items.get_key_value(item).unwrap())
+                            let (item, info) = self.items.get_key_value(item).unwrap();
+                            on_match(item, info)
                         }
                     }
                 }
             }
-            Filter::Custom { func, .. } => {
-                for (item, info) in &self.items {
-                    if func(item) {
-                        on_candidate((item, info))
-                    }
+            Filter::Custom { .. } | Filter::Expr(_) => self.filter_cache.for_each_match(filter, &self.items, on_match),
+        }
+    }
+
+    pub fn search_item<'a>(&'a self, filter: &Filter) -> Option<(&'a Rc<Item>, &'a RefCell<ItemInfo>)> {
+        let mut best: Option<(&'a Rc<Item>, &'a RefCell<ItemInfo>)> = None;
+        self.for_each_match(filter, |new_item, new_info| {
+            if let Some((_, old_info)) = best {
+                if new_info.borrow().n_stored <= old_info.borrow().n_stored {
+                    return;
                 }
             }
-        }
+            best = Some((new_item, new_info))
+        });
         best
     }
 
-    pub fn search_n_stored(&self, filter: &Filter) -> i32 {
+    // All matching items, the most stocked first.
+    pub fn search_items<'a>(&'a self, filter: &Filter) -> Vec<(&'a Rc<Item>, &'a RefCell<ItemInfo>)> {
+        let mut result = Vec::new();
+        self.for_each_match(filter, |item, info| result.push((item, info)));
+        result.sort_by_key(|(_, info)| -info.borrow().n_stored);
+        result
+    }
+
+    pub fn search_n_stored(&self, filter: &Filter) -> i64 {
         self.search_item(filter).map_or(0, |(_, info)| info.borrow().n_stored)
     }
 
+    pub fn search_n_stored_all(&self, filter: &Filter) -> i64 {
+        let mut sum = 0;
+        self.for_each_match(filter, |_, info| sum += info.borrow().n_stored);
+        sum
+    }
+
     pub fn bus_allocate(&mut self) -> LocalReceiver<usize> {
         let (sender, receiver) = make_local_one_shot();
         self.bus_wait_queue.push_back(sender);
@@ -299,8 +693,69 @@ impl Factory {
         receiver
     }
 
+    // Pulls the smallest of each item's partial stacks into the bus, to be deposited onto the fullest ones.
+    fn start_defrag(&mut self) -> Option<ChildTask<Result<(), LocalStr>>> {
+        let now = Instant::now();
+        if self.config.defrag_moves == 0 || now < self.next_defrag {
+            return None;
+        }
+        self.next_defrag = now + self.config.defrag_interval;
+        // Only stacks in the storages deposited into first are moved, as a stack elsewhere would come back to them.
+        // There the largest other partial stack outranks any empty slot, including the one moved from.
+        let mut partials = FnvHashMap::<Rc<Item>, (i32, Vec<PartialStack>)>::default();
+        for storage in &self.storages {
+            let storage = storage.borrow();
+            let rule_prio = storage.get_deposit_rule().priority;
+            for stack in storage.get_partial_stacks() {
+                let (best_rule_prio, stacks) = partials.entry(stack.item.clone()).or_insert((rule_prio, Vec::new()));
+                if rule_prio > *best_rule_prio {
+                    *best_rule_prio = rule_prio;
+                    stacks.clear()
+                }
+                if rule_prio == *best_rule_prio {
+                    stacks.push(stack)
+                }
+            }
+        }
+        let moves = (partials.into_values().map(|(_, x)| x).filter(|x| x.len() > 1))
+            .map(|x| x.into_iter().min_by_key(|x| x.size).unwrap());
+        let mut tasks = Vec::new();
+        for PartialStack { item, size, extractor } in moves.take(self.config.defrag_moves) {
+            let Some(info) = self.items.get(&self.canonicalize(&item)) else { continue };
+            // Skips stacks that processes already took from.
+            if !info.borrow_mut().reserve_provider(&extractor, size) {
+                continue;
+            }
+            self.log(Print { text: local_fmt!("defrag: {}*{}", item.label, size), color: 0x55ABEC, beep: None });
+            let bus_slot = self.bus_allocate_idle();
+            let weak = self.weak.clone();
+            tasks.push(spawn(async move {
+                // The bus had no slot to spare.
+                let Ok(bus_slot) = bus_slot.await else { return Ok(()) };
+                let extraction = {
+                    alive!(weak, this);
+                    extractor.extract(this, size, bus_slot)
+                };
+                let result = extraction.await.unwrap();
+                alive_mut!(weak, this);
+                this.bus_deposit(once(bus_slot));
+                result
+            }))
+        }
+        Some(spawn(join_tasks(tasks)))
+    }
+
+    fn bus_allocate_idle(&mut self) -> LocalReceiver<usize> {
+        let (sender, receiver) = make_local_one_shot();
+        self.bus_idle_wait_queue.push_back(sender);
+        if self.bus_task.is_none() {
+            self.bus_task = Some(spawn(bus_main(self.weak.clone())))
+        }
+        receiver
+    }
+
     pub fn bus_free(&mut self, slot: usize) {
-        if let Some(state) = self.bus_wait_queue.pop_front() {
+        if let Some(state) = self.bus_wait_queue.pop_front().or_else(|| self.bus_idle_wait_queue.pop_front()) {
             state.send(Ok(slot))
         } else {
             self.bus_allocations.remove(&slot);
@@ -322,37 +777,102 @@ impl Factory {
         }
     }
 
-    fn deposit_item(&self, bus_slot: usize, mut stack: ItemStack, tasks: &mut Vec<ChildTask<Result<(), LocalStr>>>) {
+    fn alert_full(&self, stack: &ItemStack) {
+        if !self.full_alerted.borrow_mut().insert(stack.item.clone()) {
+            return;
+        }
+        self.log(Print {
+            text: local_fmt!("storage is full: {}*{}", stack.item.label, stack.size),
+            color: 0xFF0000,
+            beep: Some(880.0),
+        })
+    }
+
+    // Dumps resolve to the number of items actually moved.
+    fn overflow_item(&self, bus_slot: usize, stack: ItemStack, dumps: &mut Vec<ChildTask<Result<i64, LocalStr>>>) {
+        let overflow = self.config.overflows.iter().find(|(filter, _)| filter.apply(&stack.item));
+        match overflow.map_or(&Overflow::Keep, |(_, overflow)| overflow) {
+            Overflow::Dump(accesses) => {
+                self.log(Print {
+                    text: local_fmt!("overflow: {}*{}", stack.item.label, stack.size),
+                    color: 0xFF4FFF,
+                    beep: None,
+                });
+                let server = self.borrow_server();
+                let access = server.load_balance(accesses).1;
+                let action = ActionFuture::from(access.from_bus(stack.size, bus_slot, None));
+                server.enqueue_request_group(access.get_client(), vec![action.clone().into()]);
+                let weak = self.weak.clone();
+                dumps.push(spawn(async move {
+                    // A full or missing target returns no count.
+                    let n_moved = call_result::<f64>(action.await?).map_or(0, |x| x as i64);
+                    if n_moved <= 0 {
+                        alive!(weak, this);
+                        this.alert_full(&stack)
+                    }
+                    Ok(n_moved)
+                }))
+            }
+            Overflow::Keep => self.alert_full(&stack),
+        }
+    }
+
+    fn deposit_item(
+        &self,
+        bus_slot: usize,
+        mut stack: ItemStack,
+        tasks: &mut Vec<ChildTask<Result<(), LocalStr>>>,
+        dumps: &mut Vec<ChildTask<Result<i64, LocalStr>>>,
+    ) {
         self.log(Print { text: local_fmt!("{}*{}", stack.item.label, stack.size), color: 0xFFA500, beep: None });
         while stack.size > 0 {
-            let mut best: Option<(&Rc<RefCell<dyn Storage>>, i32)> = None;
-            for storage in &self.storages {
-                let Some(prio) = storage.borrow_mut().deposit_priority(&stack.item) else { continue };
-                if best.as_ref().map_or(true, |&(_, best)| prio > best) {
-                    best = Some((storage, prio))
+            let mut best: Option<(usize, i32, i64)> = None;
+            for (i_storage, storage) in self.storages.iter().enumerate() {
+                let mut storage_mut = storage.borrow_mut();
+                let rule = storage_mut.get_deposit_rule();
+                if !rule.accepts(&stack.item) {
+                    continue;
+                }
+                let rule_prio = rule.priority;
+                let Some(prio) = storage_mut.deposit_priority(&stack.item) else { continue };
+                if best.as_ref().is_none_or(|&(_, best_rule_prio, best)| (rule_prio, prio) > (best_rule_prio, best)) {
+                    best = Some((i_storage, rule_prio, prio))
                 }
             }
-            if let Some((storage, _)) = best {
-                let DepositResult { n_deposited, task } = storage.borrow_mut().deposit(self, &stack, bus_slot);
+            if let Some((i_storage, _, _)) = best {
+                let DepositResult { n_deposited, task } =
+                    self.storages[i_storage].borrow_mut().deposit(self, &stack, bus_slot);
                 stack.size -= n_deposited;
                 tasks.push(task)
             } else {
-                tasks.push(spawn(async { Err(local_str!("storage is full")) }));
+                self.overflow_item(bus_slot, stack, dumps);
                 break;
             }
         }
     }
 
-    pub fn reserve_item(&self, reason: &str, item: &Rc<Item>, size: i32) -> Reservation {
+    pub fn reserve_item(&self, reason: &str, item: &Rc<Item>, size: i64) -> Reservation {
         self.log(Print { text: local_fmt!("{reason}: {}*{size}", item.label), color: 0x55ABEC, beep: None });
+        for (i_budget, budget) in self.config.budgets.iter().enumerate() {
+            if budget.item.apply(item) {
+                self.budget_usages[i_budget].borrow_mut().push_back((Instant::now(), size))
+            }
+        }
         self.items.get(item).unwrap().borrow_mut().reserve(size)
     }
 
+    pub fn add_me_fluid_storage(&mut self, config: MEFluidConfig) {
+        self.me_fluid_storages.push(MEFluidStorage::new(config, self))
+    }
+
     pub fn search_n_fluid(&self, fluid: &str) -> i64 {
         let mut sum = 0;
+        for storage in &self.me_fluid_storages {
+            sum += storage.borrow().get_n_stored(fluid)
+        }
         for storage in &self.fluid_storages {
             let storage = storage.borrow();
-            if storage.config.fluid == fluid {
+            if storage.fluid.as_deref() == Some(fluid) {
                 sum += storage.n_stored_lo
             }
         }
@@ -362,7 +882,9 @@ impl Factory {
     pub fn get_fluid_availability(&self, fluid: &str, allow_backup: bool, extra_backup: i64) -> i64 {
         let mut n_available = self.search_n_fluid(fluid) - extra_backup;
         if !allow_backup {
-            n_available -= self.fluid_backups.get(fluid).copied().unwrap_or_default()
+            n_available -= self
+                .get_override(OverrideKind::FluidBackup, fluid)
+                .unwrap_or_else(|| self.fluid_backups.get(fluid).copied().unwrap_or_default())
         }
         n_available.max(0)
     }
@@ -410,18 +932,23 @@ impl Factory {
         self.log(Print { text: local_fmt!("{fluid}*{qty}"), color: 0xFFA500, beep: None });
         let server = self.config.server.borrow();
         while qty > 0 {
-            let mut best: Option<(&Rc<RefCell<FluidStorage>>, i64)> = None;
+            let mut best = None;
             for storage in &self.fluid_storages {
                 let sto = storage.borrow();
-                if sto.config.fluid == fluid
-                    && sto.n_stored_hi < sto.capacity
-                    && best.as_ref().map_or(true, |&(_, best)| sto.n_stored_hi > best)
-                {
-                    best = Some((storage, sto.n_stored_hi))
+                // Tanks already holding the fluid come before empty generic ones.
+                let matches = match sto.fluid {
+                    Some(ref x) if *x == fluid => true,
+                    None => false,
+                    _ => continue,
+                };
+                let key = (matches, sto.n_stored_hi);
+                if sto.n_stored_hi < sto.capacity && best.as_ref().is_none_or(|&(_, best)| key > best) {
+                    best = Some((storage, key))
                 }
             }
             if let Some((storage, _)) = best {
                 let mut sto = storage.borrow_mut();
+                sto.fluid.get_or_insert_with(|| fluid.clone());
                 let n_deposited = qty.min(sto.capacity - sto.n_stored_hi);
                 sto.n_stored_hi += n_deposited;
                 qty -= n_deposited;
@@ -439,6 +966,12 @@ impl Factory {
                 });
                 server.enqueue_request_group(&access.client, vec![task.clone().into()]);
                 tasks.push(spawn(async move { task.await.map(|_| ()) }))
+            } else if !self.me_fluid_storages.is_empty() {
+                let storages = Vec::from_iter(self.me_fluid_storages.iter().map(|x| x.borrow()));
+                let accesses = Vec::from_iter(storages.iter().flat_map(|x| x.get_accesses()));
+                let access = server.load_balance(accesses.iter().copied()).1;
+                tasks.push(MEFluidStorage::deposit(&server, access, bus, slot, fluid, qty));
+                break;
             } else {
                 tasks.push(spawn(async move { Err(local_fmt!("{fluid} is full")) }));
                 break;
@@ -453,19 +986,29 @@ impl Factory {
             let mut best = None;
             for storage in &self.fluid_storages {
                 let sto = storage.borrow();
-                if sto.config.fluid == fluid
+                if sto.fluid.as_deref() == Some(fluid)
                     && sto.n_stored_lo > 0
-                    && best.as_ref().map_or(true, |&(_, best)| sto.n_stored_lo < best)
+                    && best.as_ref().is_none_or(|&(_, best)| sto.n_stored_lo < best)
                 {
                     best = Some((storage.clone(), sto.n_stored_lo))
                 }
             }
-            let (storage, _) = best.unwrap();
+            let Some((storage, _)) = best else { break };
             let mut storage = storage.borrow_mut();
             let to_reserve = qty.min(storage.n_stored_lo);
             storage.n_stored_lo -= to_reserve;
             qty -= to_reserve;
-            extractors.push((storage.weak.clone(), to_reserve))
+            extractors.push((Rc::new(TankExtractor { weak: storage.weak.clone() }) as _, to_reserve))
+        }
+        for storage in &self.me_fluid_storages {
+            if qty <= 0 {
+                break;
+            }
+            let (extractor, to_reserve) = storage.borrow_mut().reserve(fluid, qty);
+            if to_reserve > 0 {
+                qty -= to_reserve;
+                extractors.push((extractor, to_reserve))
+            }
         }
         FluidReservation { extractors }
     }
@@ -474,6 +1017,9 @@ impl Factory {
         for storage in &self.storages {
             storage.borrow_mut().cleanup()
         }
+        for storage in &self.me_fluid_storages {
+            storage.borrow_mut().cleanup()
+        }
         for storage in &self.fluid_storages {
             let mut storage = storage.borrow_mut();
             storage.slot = None;
@@ -484,6 +1030,12 @@ impl Factory {
         self.items.clear();
         self.label_map.clear();
         self.name_map.clear();
+        self.filter_cache.clear();
+        self.interned.get_mut().clear();
+        self.grants.clear();
+        let seen = take(self.batch_seen.get_mut());
+        self.batch_waits.get_mut().retain(|key, _| seen.contains(key));
+        self.in_flight = take(&mut self.in_flight_next);
     }
 }
 
@@ -506,14 +1058,42 @@ async fn factory_main(factory: Weak<RefCell<Factory>>) -> Result<(), LocalStr> {
                 local_str!("OCRemote started")
             };
             this.log(Print { text, color: 0xFFFFFF, beep: None });
+            let mut input_queue = this.config.tui.input_queue.borrow_mut();
+            let (commands, rest): (Vec<_>, _) = take(&mut *input_queue).into_iter().partition(|x| x.starts_with('/'));
+            *input_queue = rest;
+            drop(input_queue);
+            for command in commands {
+                this.handle_command(command[1..].trim())
+            }
             this.n_bus_updates = 0;
             this.n_fluid_bus_updates = 0
         }
         let result = async {
             update_storages(&factory).await?;
+            prepare_processes(&factory).await?;
+            {
+                alive_mut!(factory, this);
+                this.arbitrate()
+            }
             run_processes(&factory).await
         }
         .await;
+        // Started once the processes are done so that its reservations don't skew their demands.
+        // Its moves deposit through the bus, so they finish before the last bus update is awaited.
+        let defrag_task = {
+            alive_mut!(factory, this);
+            if result.is_ok() {
+                this.start_defrag()
+            } else {
+                None
+            }
+        };
+        if let Some(defrag_task) = defrag_task {
+            if let Err(e) = defrag_task.await.unwrap() {
+                alive!(factory, this);
+                this.log(Print { text: local_fmt!("defrag failed: {}", e), color: 0xFF0000, beep: Some(880.0) })
+            }
+        }
         let mut bus_task;
         let mut fluid_bus_task;
         {
@@ -550,10 +1130,11 @@ async fn update_storages(factory: &Weak<RefCell<Factory>>) -> Result<(), LocalSt
     {
         alive!(factory, this);
         tasks.extend(this.storages.iter().map(|storage| storage.borrow().update(this)));
-        tasks.extend(this.fluid_storages.iter().map(|storage| storage.borrow().update()))
+        tasks.extend(this.fluid_storages.iter().map(|storage| storage.borrow().update()));
+        tasks.extend(this.me_fluid_storages.iter().map(|storage| storage.borrow().update(this)))
     };
     join_tasks(tasks).await?;
-    alive!(factory, this);
+    alive_mut!(factory, this);
     let mut n_total = 0;
     for (_, item) in &this.items {
         n_total += item.borrow().n_stored
@@ -563,14 +1144,64 @@ async fn update_storages(factory: &Weak<RefCell<Factory>>) -> Result<(), LocalSt
         color: 0x00FF00,
         beep: None,
     });
+    this.storage_usages = Vec::from_iter(this.storages.iter().map(|storage| {
+        let storage = storage.borrow();
+        (storage.describe(), storage.get_usage())
+    }));
+    let n_free: usize = this.storage_usages.iter().flat_map(|(_, usage)| usage).map(|x| x.n_total - x.n_used).sum();
+    if n_free < this.config.min_free_slots {
+        this.log(Print { text: local_fmt!("storage: {} slots free", n_free), color: 0xFF0000, beep: Some(880.0) })
+    }
+    let mut configured = FnvHashSet::default();
     for (filter, n_backup) in &this.config.backups {
+        let key = filter.to_string();
+        let n_backup = this.overrides.get(OverrideKind::Backup, &key).unwrap_or(*n_backup);
         if let Some((_, info)) = this.search_item(filter) {
             info.borrow_mut().n_backup += n_backup
         }
+        configured.insert(key);
+    }
+    if this.config.seed_backup > 0 {
+        let seeds = this.find_seeds();
+        for seed in &seeds {
+            if !this.seeds.contains(seed) {
+                this.log(Print { text: local_fmt!("seed: {}", seed.label), color: 0x55ABEC, beep: None })
+            }
+        }
+        this.seeds = seeds
+    }
+    for seed in &this.seeds {
+        // Overrides for seeds are keyed by label.
+        let key = seed.label.to_string();
+        if this.config.backups.iter().any(|(filter, _)| filter.apply(seed)) || !configured.insert(key.clone()) {
+            continue;
+        }
+        let n_backup = this.overrides.get(OverrideKind::Backup, &key).unwrap_or(this.config.seed_backup);
+        if let Some(info) = this.items.get(seed) {
+            info.borrow_mut().n_backup += n_backup
+        }
+    }
+    // Overrides for items without a configured backup are keyed by filter expressions.
+    for (kind, key, n_backup) in this.overrides.iter() {
+        if kind != OverrideKind::Backup || configured.contains(&**key) {
+            continue;
+        }
+        let Ok(expr) = key.parse() else { continue };
+        if let Some((_, info)) = this.search_item(&Filter::Expr(Rc::new(expr))) {
+            info.borrow_mut().n_backup += n_backup
+        }
     }
     Ok(())
 }
 
+async fn prepare_processes(factory: &Weak<RefCell<Factory>>) -> Result<(), LocalStr> {
+    let tasks = {
+        alive!(factory, this);
+        this.processes.iter().map(|process| process.borrow().prepare(this)).collect()
+    };
+    join_tasks(tasks).await
+}
+
 async fn run_processes(factory: &Weak<RefCell<Factory>>) -> Result<(), LocalStr> {
     let tasks = {
         alive!(factory, this);
@@ -586,13 +1217,17 @@ async fn bus_main(factory: Weak<RefCell<Factory>>) -> Result<(), LocalStr> {
         match result {
             Err(e) => {
                 let text = local_fmt!("bus update failed: {}", e);
-                for sender in take(&mut this.bus_wait_queue) {
+                for sender in take(&mut this.bus_wait_queue).into_iter().chain(take(&mut this.bus_idle_wait_queue)) {
                     sender.send(Err(text.clone()))
                 }
                 this.log(Print { text, color: 0xFF0000, beep: Some(880.0) });
             }
             Ok(true) => continue,
-            Ok(false) => (),
+            Ok(false) => {
+                for sender in take(&mut this.bus_idle_wait_queue) {
+                    sender.send(Err(local_str!("bus is busy")))
+                }
+            }
         }
         this.bus_task = None;
         break Ok(());
@@ -606,18 +1241,22 @@ async fn bus_update(factory: &Weak<RefCell<Factory>>) -> Result<bool, LocalStr>
         this.n_bus_updates += 1;
         let server = this.borrow_server();
         let access = server.load_balance(&this.config.bus_accesses).1;
-        action = ActionFuture::from(List { addr: access.addr.clone(), side: access.side });
-        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        action = ActionFuture::from(access.list());
+        server.enqueue_request_group(access.get_client(), vec![action.clone().into()]);
     }
-    let stacks = action.await?;
+    let mut stacks = action.await?;
     let mut tasks = Vec::new();
+    let mut dumps = Vec::new();
     {
         alive_mut!(factory, this);
+        this.canonicalize_stacks(&mut stacks);
+        let on_bus = FnvHashSet::from_iter(stacks.iter().flatten().map(|stack| &stack.item));
+        this.full_alerted.get_mut().retain(|item| on_bus.contains(item));
         let mut free_slots = Vec::new();
         for (slot, stack) in stacks.into_iter().enumerate() {
             if !this.bus_allocations.contains(&slot) {
                 if let Some(stack) = stack {
-                    this.deposit_item(slot, stack, &mut tasks);
+                    this.deposit_item(slot, stack, &mut tasks, &mut dumps);
                 } else {
                     free_slots.push(slot)
                 }
@@ -628,16 +1267,22 @@ async fn bus_update(factory: &Weak<RefCell<Factory>>) -> Result<bool, LocalStr>
             this.bus_allocations.insert(slot);
             this.bus_wait_queue.pop_front().unwrap().send(Ok(slot))
         }
+        while !free_slots.is_empty() && !this.bus_idle_wait_queue.is_empty() {
+            let slot = free_slots.pop().unwrap();
+            this.bus_allocations.insert(slot);
+            this.bus_idle_wait_queue.pop_front().unwrap().send(Ok(slot))
+        }
     }
-    let ever_deposited = !tasks.is_empty();
+    let mut ever_deposited = !tasks.is_empty();
     join_tasks(tasks).await?;
+    ever_deposited |= join_outputs(dumps).await?.into_iter().any(|n_moved| n_moved > 0);
     alive_mut!(factory, this);
     let mut ever_freed = false;
     for slot in take(&mut this.bus_free_queue) {
         this.bus_allocations.remove(&slot);
         ever_freed = true
     }
-    Ok(ever_freed || ever_deposited && !this.bus_wait_queue.is_empty())
+    Ok(ever_freed || ever_deposited && !(this.bus_wait_queue.is_empty() && this.bus_idle_wait_queue.is_empty()))
 }
 
 async fn fluid_bus_main(factory: Weak<RefCell<Factory>>) -> Result<(), LocalStr> {
@@ -761,9 +1406,15 @@ impl FluidStorage {
         spawn(async move {
             let tanks = task.await?;
             alive_mut!(weak, this);
+            if this.config.fluid.is_none() {
+                this.fluid = None
+            }
             for (i, tank) in tanks.into_iter().enumerate() {
                 if let Some(fluid) = tank.fluid {
-                    if fluid != this.config.fluid {
+                    if this.fluid.is_none() {
+                        this.fluid = Some(fluid.clone())
+                    }
+                    if this.fluid.as_ref() != Some(&fluid) {
                         upgrade!(this.factory, this);
                         this.log(Print {
                             text: local_fmt!("unexpected {fluid} stored"),
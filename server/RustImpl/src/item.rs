@@ -1,6 +1,14 @@
-use super::lua_value::{table_remove, Table, Value};
-use flexstr::LocalStr;
-use std::{cmp::min, rc::Rc};
+use super::lua_value::{table_remove, Key, Table, Value};
+use flexstr::{local_fmt, local_str, LocalStr};
+use fnv::FnvHashMap;
+use regex::Regex;
+use std::{
+    cell::RefCell,
+    cmp::min,
+    fmt::{self, Display, Formatter},
+    rc::Rc,
+    str::FromStr,
+};
 
 #[derive(PartialEq, Eq, Hash)]
 pub struct Item {
@@ -8,7 +16,7 @@ pub struct Item {
     pub name: LocalStr,
     pub damage: i16,
     pub max_damage: i16,
-    pub max_size: i32,
+    pub max_size: i64,
     pub has_tag: bool,
     pub others: Table,
 }
@@ -26,6 +34,65 @@ impl Item {
     }
 }
 
+// Which fields of the leftover `others` table take part in an item's identity.
+#[derive(Clone)]
+pub enum OthersPolicy {
+    All,
+    Only(Vec<LocalStr>),
+    Except(Vec<LocalStr>),
+}
+
+// Which fields make up an item's identity when it's registered in the factory.
+// Label, name and max size always take part.
+#[derive(Clone)]
+pub struct IdentityPolicy {
+    pub damage: bool,
+    // hasTag and the "tag" entry of others.
+    pub tag: bool,
+    pub others: OthersPolicy,
+}
+
+impl Default for IdentityPolicy {
+    fn default() -> Self { IdentityPolicy { damage: true, tag: true, others: OthersPolicy::All } }
+}
+
+impl IdentityPolicy {
+    pub fn ignore_damage() -> Self { IdentityPolicy { damage: false, ..<_>::default() } }
+    pub fn ignore_tag() -> Self { IdentityPolicy { tag: false, ..<_>::default() } }
+    pub fn ignore_others() -> Self { IdentityPolicy { others: OthersPolicy::Only(Vec::new()), ..<_>::default() } }
+
+    // None if the item is already canonical under this policy.
+    pub fn canonicalize(&self, item: &Item) -> Option<Item> {
+        if self.damage && self.tag && matches!(self.others, OthersPolicy::All) {
+            return None;
+        }
+        let mut others = Table::new();
+        for (key, value) in &item.others {
+            let keep = match key {
+                Key::S(key) if key == "tag" => self.tag,
+                Key::S(key) => match &self.others {
+                    OthersPolicy::All => true,
+                    OthersPolicy::Only(keys) => keys.contains(key),
+                    OthersPolicy::Except(keys) => !keys.contains(key),
+                },
+                _ => matches!(self.others, OthersPolicy::All | OthersPolicy::Except(_)),
+            };
+            if keep {
+                others.insert(key.clone(), value.clone());
+            }
+        }
+        Some(Item {
+            label: item.label.clone(),
+            name: item.name.clone(),
+            damage: if self.damage { item.damage } else { 0 },
+            max_damage: item.max_damage,
+            max_size: item.max_size,
+            has_tag: self.tag && item.has_tag,
+            others,
+        })
+    }
+}
+
 pub fn jammer() -> Rc<Item> {
     thread_local!(static ITEM: Rc<Item> = Rc::new(Item {
         label: <_>::default(),
@@ -42,7 +109,7 @@ pub fn jammer() -> Rc<Item> {
 #[derive(Clone)]
 pub struct ItemStack {
     pub item: Rc<Item>,
-    pub size: i32,
+    pub size: i64,
 }
 
 impl ItemStack {
@@ -63,11 +130,11 @@ impl ItemStack {
 }
 
 pub struct InsertPlan {
-    pub n_inserted: i32,
-    pub insertions: Vec<(usize, i32)>,
+    pub n_inserted: i64,
+    pub insertions: Vec<(usize, i64)>,
 }
 
-pub fn insert_into_inventory(inventory: &mut Vec<Option<ItemStack>>, item: &Rc<Item>, to_insert: i32) -> InsertPlan {
+pub fn insert_into_inventory(inventory: &mut Vec<Option<ItemStack>>, item: &Rc<Item>, to_insert: i64) -> InsertPlan {
     let mut result = InsertPlan { n_inserted: 0, insertions: Vec::new() };
     let mut remaining = min(to_insert, item.max_size);
     let mut first_empty_slot = None;
@@ -105,6 +172,7 @@ pub enum Filter {
     Name(LocalStr),
     Both { label: LocalStr, name: LocalStr },
     Custom { desc: LocalStr, func: Rc<dyn Fn(&Item) -> bool> },
+    Expr(Rc<FilterExpr>),
 }
 
 impl Filter {
@@ -114,6 +182,412 @@ impl Filter {
             Filter::Name(name) => item.name == *name,
             Filter::Both { label, name } => item.label == *label && item.name == *name,
             Filter::Custom { func, .. } => func(item),
+            Filter::Expr(expr) => expr.apply(item),
+        }
+    }
+}
+
+// Tells filters apart without formatting them: by value for plain ones and by the shared payload otherwise.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum FilterKey {
+    Label(LocalStr),
+    Name(LocalStr),
+    Both(LocalStr, LocalStr),
+    Shared(*const ()),
+}
+
+impl Filter {
+    pub fn key(&self) -> FilterKey {
+        match self {
+            Filter::Label(label) => FilterKey::Label(label.clone()),
+            Filter::Name(name) => FilterKey::Name(name.clone()),
+            Filter::Both { label, name } => FilterKey::Both(label.clone(), name.clone()),
+            Filter::Custom { func, .. } => FilterKey::Shared(Rc::as_ptr(func) as *const ()),
+            Filter::Expr(expr) => FilterKey::Shared(Rc::as_ptr(expr) as *const ()),
+        }
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Filter::Label(label) => write!(f, "{}", label),
+            Filter::Name(name) => write!(f, "<{}>", name),
+            Filter::Both { label, name } => write!(f, "{} <{}>", label, name),
+            Filter::Custom { desc, .. } => write!(f, "<{}>", desc),
+            Filter::Expr(expr) => write!(f, "[{}]", expr),
+        }
+    }
+}
+
+// Memoized matches of filters that can't be looked up by label or name.
+// Entries must be told about new items and cleared whenever items are removed.
+#[derive(Default)]
+pub struct FilterCache {
+    // Keyed by the address of the filter's shared payload.
+    entries: RefCell<FnvHashMap<*const (), FilterCacheEntry>>,
+}
+
+struct FilterCacheEntry {
+    // Kept to pin the address used as the key.
+    filter: Filter,
+    matches: Vec<Rc<Item>>,
+}
+
+impl FilterCache {
+    fn key(filter: &Filter) -> Option<*const ()> {
+        match filter {
+            Filter::Custom { func, .. } => Some(Rc::as_ptr(func) as *const ()),
+            Filter::Expr(expr) => Some(Rc::as_ptr(expr) as *const ()),
+            _ => None,
+        }
+    }
+
+    pub fn for_each_match<'a, V>(
+        &self,
+        filter: &Filter,
+        items: &'a FnvHashMap<Rc<Item>, V>,
+        mut on_match: impl FnMut(&'a Rc<Item>, &'a V),
+    ) {
+        let Some(key) = Self::key(filter) else {
+            for (item, value) in items {
+                if filter.apply(item) {
+                    on_match(item, value)
+                }
+            }
+            return;
+        };
+        let mut entries = self.entries.borrow_mut();
+        let entry = entries.entry(key).or_insert_with(|| FilterCacheEntry {
+            filter: filter.clone(),
+            matches: Vec::from_iter(items.keys().filter(|item| filter.apply(item)).cloned()),
+        });
+        for item in &entry.matches {
+            let (item, value) = items.get_key_value(item).unwrap();
+            on_match(item, value)
+        }
+    }
+
+    pub fn on_new_item(&self, item: &Rc<Item>) {
+        for entry in self.entries.borrow_mut().values_mut() {
+            if entry.filter.apply(item) {
+                entry.matches.push(item.clone())
+            }
+        }
+    }
+
+    pub fn clear(&mut self) { self.entries.get_mut().clear() }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+}
+
+impl FilterOp {
+    const ALL: [(&'static str, FilterOp); 7] = [
+        ("!=", FilterOp::Ne),
+        ("<=", FilterOp::Le),
+        (">=", FilterOp::Ge),
+        ("=", FilterOp::Eq),
+        ("<", FilterOp::Lt),
+        (">", FilterOp::Gt),
+        ("~", FilterOp::Match),
+    ];
+
+    fn text(self) -> &'static str { Self::ALL.iter().find(|(_, op)| *op == self).unwrap().0 }
+
+    fn compare<T: PartialOrd + ?Sized>(self, lhs: &T, rhs: &T) -> bool {
+        match self {
+            FilterOp::Eq => lhs == rhs,
+            FilterOp::Ne => lhs != rhs,
+            FilterOp::Lt => lhs < rhs,
+            FilterOp::Le => lhs <= rhs,
+            FilterOp::Gt => lhs > rhs,
+            FilterOp::Ge => lhs >= rhs,
+            FilterOp::Match => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum FilterField {
+    Label,
+    Name,
+    Damage,
+    // damage / maxDamage, or 0 for undamageable items.
+    DamageRatio,
+    Other(LocalStr),
+}
+
+enum FieldValue<'a> {
+    Str(&'a str),
+    Num(f64),
+}
+
+impl FilterField {
+    fn get<'a>(&self, item: &'a Item) -> Option<FieldValue<'a>> {
+        match self {
+            FilterField::Label => Some(FieldValue::Str(&item.label)),
+            FilterField::Name => Some(FieldValue::Str(&item.name)),
+            FilterField::Damage => Some(FieldValue::Num(item.damage.into())),
+            FilterField::DamageRatio => Some(FieldValue::Num(if item.max_damage > 0 {
+                f64::from(item.damage) / f64::from(item.max_damage)
+            } else {
+                0.
+            })),
+            FilterField::Other(key) => match item.others.get(&key.clone().into())? {
+                Value::S(x) => Some(FieldValue::Str(x)),
+                Value::F(x) => Some(FieldValue::Num(x.into_inner())),
+                Value::B(x) => Some(FieldValue::Str(if *x { "true" } else { "false" })),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Display for FilterField {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            FilterField::Label => write!(f, "label"),
+            FilterField::Name => write!(f, "name"),
+            FilterField::Damage => write!(f, "damage"),
+            FilterField::DamageRatio => write!(f, "damage_ratio"),
+            FilterField::Other(key) => write!(f, "others.{}", key),
+        }
+    }
+}
+
+// Text form, loosest binding first:
+//   a | b, a & b, !a, (a)
+//   field op value, where op is one of = != < <= > >= ~ (regex)
+//     and field is label, name, damage, damage_ratio or others.<key>
+//   has_tag
+//   value alone, short for `label ~ value`
+// Values are bare words or "quoted strings" with \ escapes.
+#[derive(Clone)]
+pub enum FilterExpr {
+    HasTag,
+    Compare { field: FilterField, op: FilterOp, value: LocalStr, number: Option<f64>, regex: Option<Regex> },
+    Not(Box<FilterExpr>),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+}
+
+impl FilterExpr {
+    pub fn apply(&self, item: &Item) -> bool {
+        match self {
+            FilterExpr::HasTag => item.has_tag,
+            FilterExpr::Compare { field, op, value, number, regex } => match field.get(item) {
+                None => *op == FilterOp::Ne,
+                Some(FieldValue::Str(x)) => {
+                    if let Some(regex) = regex {
+                        regex.is_match(x)
+                    } else {
+                        op.compare(x, value)
+                    }
+                }
+                Some(FieldValue::Num(x)) => {
+                    if let Some(regex) = regex {
+                        regex.is_match(&x.to_string())
+                    } else {
+                        number.is_some_and(|number| op.compare(&x, &number))
+                    }
+                }
+            },
+            FilterExpr::Not(x) => !x.apply(item),
+            FilterExpr::And(xs) => xs.iter().all(|x| x.apply(item)),
+            FilterExpr::Or(xs) => xs.iter().any(|x| x.apply(item)),
+        }
+    }
+
+    pub fn compare(field: FilterField, op: FilterOp, value: LocalStr) -> Result<Self, LocalStr> {
+        let regex =
+            if op == FilterOp::Match { Some(Regex::new(&value).map_err(|e| local_fmt!("{}", e))?) } else { None };
+        let number = value.parse().ok();
+        if number.is_none() && matches!(op, FilterOp::Lt | FilterOp::Le | FilterOp::Gt | FilterOp::Ge) {
+            return Err(local_fmt!("not a number: {}", value));
+        }
+        Ok(FilterExpr::Compare { field, op, value, number, regex })
+    }
+}
+
+#[derive(PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    Op(FilterOp),
+    Word(LocalStr),
+}
+
+fn is_word_char(c: char) -> bool { !c.is_whitespace() && !"()!&|=<>~\"".contains(c) }
+
+fn tokenize(text: &str) -> Result<Vec<Token>, LocalStr> {
+    let mut result = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if let Some((text, op)) = FilterOp::ALL.iter().find(|(text, _)| rest.starts_with(text)) {
+            result.push(Token::Op(*op));
+            rest = &rest[text.len()..]
+        } else if let Some(token) = match c {
+            '(' => Some(Token::Open),
+            ')' => Some(Token::Close),
+            '!' => Some(Token::Not),
+            '&' => Some(Token::And),
+            '|' => Some(Token::Or),
+            _ => None,
+        } {
+            result.push(token);
+            rest = &rest[1..]
+        } else if c == '"' {
+            let mut word = String::new();
+            let mut chars = rest[1..].char_indices();
+            loop {
+                match chars.next() {
+                    None => return Err(local_fmt!("unterminated string: {}", rest)),
+                    Some((i, '"')) => {
+                        rest = &rest[i + 2..];
+                        break;
+                    }
+                    Some((_, '\\')) => word.extend(chars.next().map(|(_, c)| c)),
+                    Some((_, c)) => word.push(c),
+                }
+            }
+            result.push(Token::Word(word.into()))
+        } else {
+            let len = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+            result.push(Token::Word(rest[..len].into()));
+            rest = &rest[len..]
+        }
+        rest = rest.trim_start()
+    }
+    Ok(result)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> { self.tokens.get(self.pos) }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let result = self.peek() == Some(token);
+        self.pos += result as usize;
+        result
+    }
+
+    fn parse_or(&mut self) -> Result<FilterExpr, LocalStr> {
+        let mut xs = vec![self.parse_and()?];
+        while self.eat(&Token::Or) {
+            xs.push(self.parse_and()?)
+        }
+        Ok(if xs.len() == 1 { xs.pop().unwrap() } else { FilterExpr::Or(xs) })
+    }
+
+    fn parse_and(&mut self) -> Result<FilterExpr, LocalStr> {
+        let mut xs = vec![self.parse_unary()?];
+        while self.eat(&Token::And) {
+            xs.push(self.parse_unary()?)
+        }
+        Ok(if xs.len() == 1 { xs.pop().unwrap() } else { FilterExpr::And(xs) })
+    }
+
+    fn parse_unary(&mut self) -> Result<FilterExpr, LocalStr> {
+        if self.eat(&Token::Not) {
+            return Ok(FilterExpr::Not(Box::new(self.parse_unary()?)));
+        } else if self.eat(&Token::Open) {
+            let result = self.parse_or()?;
+            return if self.eat(&Token::Close) { Ok(result) } else { Err(local_str!("expected )")) };
+        }
+        let Some(Token::Word(word)) = self.peek() else { return Err(local_str!("expected a term")) };
+        let word = word.clone();
+        self.pos += 1;
+        let Some(&Token::Op(op)) = self.peek() else {
+            return if word == "has_tag" {
+                Ok(FilterExpr::HasTag)
+            } else {
+                FilterExpr::compare(FilterField::Label, FilterOp::Match, word)
+            };
+        };
+        self.pos += 1;
+        let field = match &*word {
+            "label" => FilterField::Label,
+            "name" => FilterField::Name,
+            "damage" => FilterField::Damage,
+            "damage_ratio" => FilterField::DamageRatio,
+            _ => match word.strip_prefix("others.") {
+                Some(key) => FilterField::Other(key.into()),
+                None => return Err(local_fmt!("unknown field: {}", word)),
+            },
+        };
+        let Some(Token::Word(value)) = self.peek() else { return Err(local_fmt!("expected a value after {}", word)) };
+        let value = value.clone();
+        self.pos += 1;
+        FilterExpr::compare(field, op, value)
+    }
+}
+
+impl FromStr for FilterExpr {
+    type Err = LocalStr;
+    fn from_str(text: &str) -> Result<Self, LocalStr> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0 };
+        let result = parser.parse_or()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(local_fmt!("unexpected trailing input in: {}", text));
+        }
+        Ok(result)
+    }
+}
+
+fn fmt_value(value: &str, f: &mut Formatter) -> fmt::Result {
+    if !value.is_empty() && value.chars().all(is_word_char) {
+        write!(f, "{}", value)
+    } else {
+        write!(f, "\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+fn fmt_operand(x: &FilterExpr, f: &mut Formatter) -> fmt::Result {
+    if matches!(x, FilterExpr::And(_) | FilterExpr::Or(_)) {
+        write!(f, "({})", x)
+    } else {
+        write!(f, "{}", x)
+    }
+}
+
+impl Display for FilterExpr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            FilterExpr::HasTag => write!(f, "has_tag"),
+            FilterExpr::Compare { field, op, value, .. } => {
+                write!(f, "{} {} ", field, op.text())?;
+                fmt_value(value, f)
+            }
+            FilterExpr::Not(x) => {
+                write!(f, "!")?;
+                fmt_operand(x, f)
+            }
+            FilterExpr::And(xs) | FilterExpr::Or(xs) => {
+                let sep = if matches!(self, FilterExpr::And(_)) { " & " } else { " | " };
+                for (i, x) in xs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "{}", sep)?
+                    }
+                    fmt_operand(x, f)?
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -1,3 +1,4 @@
+#![cfg_attr(feature = "bench", feature(test))]
 #[macro_use]
 pub mod util;
 #[macro_use]
@@ -6,10 +7,13 @@ pub mod recipe;
 pub mod config_util;
 pub mod access;
 pub mod action;
+#[cfg(all(test, feature = "bench"))]
+mod bench;
 pub mod config;
 pub mod factory;
 pub mod item;
 pub mod lua_value;
+pub mod overrides;
 pub mod process;
 pub mod server;
 pub mod side;
@@ -0,0 +1,114 @@
+use flexstr::{local_fmt, LocalStr};
+use std::{collections::BTreeMap, fs::read_to_string, str::FromStr};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OverrideKind {
+    // Keyed by the displayed filter of an `Output`.
+    Target,
+    // Keyed by fluid name.
+    FluidTarget,
+    // Keyed by the displayed filter of a configured backup, or by a filter expression.
+    Backup,
+    // Keyed by fluid name.
+    FluidBackup,
+}
+
+impl OverrideKind {
+    const ALL: [(&'static str, OverrideKind); 4] = [
+        ("target", OverrideKind::Target),
+        ("fluid_target", OverrideKind::FluidTarget),
+        ("backup", OverrideKind::Backup),
+        ("fluid_backup", OverrideKind::FluidBackup),
+    ];
+
+    pub fn name(self) -> &'static str { Self::ALL.iter().find(|(_, kind)| *kind == self).unwrap().0 }
+}
+
+impl FromStr for OverrideKind {
+    type Err = LocalStr;
+    fn from_str(name: &str) -> Result<Self, LocalStr> {
+        let kind = Self::ALL.iter().find(|(x, _)| *x == name);
+        kind.map(|(_, kind)| *kind).ok_or_else(|| local_fmt!("unknown override kind: {}", name))
+    }
+}
+
+// Quantities set from the TUI on top of the configured ones.
+// Saved to `file_name` as lines of "<kind> <qty> <key>".
+pub struct Overrides {
+    file_name: Option<LocalStr>,
+    values: BTreeMap<(OverrideKind, LocalStr), i64>,
+}
+
+fn parse_line(line: &str) -> Result<(OverrideKind, i64, LocalStr), LocalStr> {
+    let mut parts = line.splitn(3, ' ');
+    let kind = parts.next().unwrap_or_default().parse()?;
+    let qty = parts.next().unwrap_or_default();
+    let qty = qty.parse().map_err(|_| local_fmt!("invalid quantity: {}", qty))?;
+    let key = parts.next().unwrap_or_default().trim();
+    if key.is_empty() {
+        return Err(local_fmt!("missing key: {}", line));
+    }
+    Ok((kind, qty, key.into()))
+}
+
+impl Overrides {
+    // Lines that fail to parse are skipped and reported.
+    pub fn load(file_name: Option<LocalStr>) -> (Self, Vec<LocalStr>) {
+        let mut values = BTreeMap::new();
+        let mut errors = Vec::new();
+        if let Some(file_name) = &file_name {
+            if let Ok(text) = read_to_string(&**file_name) {
+                for line in text.lines().filter(|x| !x.trim().is_empty()) {
+                    match parse_line(line) {
+                        Ok((kind, qty, key)) => {
+                            values.insert((kind, key), qty);
+                        }
+                        Err(e) => errors.push(local_fmt!("{}: {}", file_name, e)),
+                    }
+                }
+            }
+        }
+        (Overrides { file_name, values }, errors)
+    }
+
+    fn save(&self) -> Result<(), LocalStr> {
+        let Some(file_name) = &self.file_name else { return Ok(()) };
+        let text = String::from_iter(self.iter().map(|(kind, key, qty)| format!("{} {} {}\n", kind.name(), qty, key)));
+        std::fs::write(&**file_name, text).map_err(|e| local_fmt!("{}: {}", file_name, e))
+    }
+
+    pub fn is_empty(&self) -> bool { self.values.is_empty() }
+
+    pub fn get(&self, kind: OverrideKind, key: &str) -> Option<i64> { self.values.get(&(kind, key.into())).copied() }
+
+    pub fn iter(&self) -> impl Iterator<Item = (OverrideKind, &LocalStr, i64)> {
+        self.values.iter().map(|((kind, key), qty)| (*kind, key, *qty))
+    }
+
+    // "set <kind> <qty> <key>" or "clear <kind> <key>"; returns what was changed.
+    // `validate` rejects keys that wouldn't apply to anything.
+    pub fn apply_command(
+        &mut self,
+        command: &str,
+        validate: impl FnOnce(OverrideKind, &str) -> Result<(), LocalStr>,
+    ) -> Result<LocalStr, LocalStr> {
+        let result = if let Some(rest) = command.strip_prefix("set ") {
+            let (kind, qty, key) = parse_line(rest.trim())?;
+            validate(kind, &key)?;
+            let result = local_fmt!("{} {} = {}", kind.name(), key, qty);
+            self.values.insert((kind, key), qty);
+            result
+        } else if let Some(rest) = command.strip_prefix("clear ") {
+            let (kind, key) = rest.trim().split_once(' ').ok_or_else(|| local_fmt!("missing key: {}", rest))?;
+            let (kind, key) = (kind.parse()?, key.trim());
+            if self.values.remove(&(kind, key.into())).is_none() {
+                return Err(local_fmt!("no {} override for {}", kind.name(), key));
+            }
+            local_fmt!("{} {} cleared", kind.name(), key)
+        } else {
+            return Err(local_fmt!("unknown command: {}", command));
+        };
+        self.save()?;
+        Ok(result)
+    }
+}
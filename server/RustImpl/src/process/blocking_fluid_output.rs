@@ -35,7 +35,7 @@ impl Process for BlockingFluidOutputProcess {
     fn run(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
         let mut enough = true;
         for output in &self.config.outputs {
-            if factory.search_n_fluid(&output.fluid) < output.n_wanted {
+            if factory.search_n_fluid(&output.fluid) < output.get_n_wanted(factory) {
                 enough = false;
                 break;
             }
@@ -58,7 +58,7 @@ impl Process for BlockingFluidOutputProcess {
                 for output in &this.config.outputs {
                     let Some(&(_, slot)) = tanks.get(&output.fluid) else { continue };
                     let n_stored = factory.search_n_fluid(&output.fluid);
-                    let qty = output.n_wanted - n_stored;
+                    let qty = output.get_n_wanted(factory) - n_stored;
                     if qty > 0 {
                         let weak = weak.clone();
                         tasks.push(spawn(async move {
@@ -1,5 +1,5 @@
 use super::{extract_output, list_inv, IntoProcess, Inventory, Process, SlotFilter};
-use crate::access::InvAccess;
+use crate::access::ItemAccess;
 use crate::factory::Factory;
 use crate::item::Item;
 use crate::recipe::Output;
@@ -10,12 +10,11 @@ use fnv::FnvHashMap;
 use std::{
     cell::RefCell,
     cmp::{max, min},
-    collections::hash_map::Entry,
     rc::{Rc, Weak},
 };
 
 pub struct BlockingOutputConfig {
-    pub accesses: Vec<InvAccess>,
+    pub accesses: Vec<ItemAccess>,
     pub slot_filter: Option<SlotFilter>,
     pub outputs: Vec<Output>,
 }
@@ -37,16 +36,11 @@ impl IntoProcess for BlockingOutputConfig {
     }
 }
 
-struct Info {
-    n_stored: i32,
-    n_wanted: i32,
-}
-
 impl Process for BlockingOutputProcess {
     fn run(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
         let mut enough = true;
         for output in &self.config.outputs {
-            if factory.search_n_stored(&output.item) < output.n_wanted {
+            if output.get_n_stored(factory) < output.get_n_wanted(factory) {
                 enough = false;
                 break;
             }
@@ -62,7 +56,14 @@ impl Process for BlockingOutputProcess {
             {
                 alive!(weak, this);
                 upgrade_mut!(this.factory, factory);
-                let mut infos = FnvHashMap::<&Rc<Item>, Info>::default();
+                let mut n_stored = FnvHashMap::<&Rc<Item>, i64>::default();
+                let mut n_stored_all = Vec::from_iter(this.config.outputs.iter().map(|output| {
+                    if output.aggregate {
+                        output.get_n_stored(factory)
+                    } else {
+                        0
+                    }
+                }));
                 for (slot, stack) in stacks.iter().enumerate() {
                     if let Some(ref slot_filter) = this.config.slot_filter {
                         if !slot_filter(slot) {
@@ -70,23 +71,25 @@ impl Process for BlockingOutputProcess {
                         }
                     }
                     if let Some(stack) = stack {
-                        let info = match infos.entry(&stack.item) {
-                            Entry::Occupied(entry) => entry.into_mut(),
-                            Entry::Vacant(entry) => {
-                                let mut info = Info { n_wanted: 0, n_stored: factory.get_n_stored(&stack.item) };
-                                for output in &this.config.outputs {
-                                    if output.item.apply(&stack.item) {
-                                        info.n_wanted = max(info.n_wanted, output.n_wanted)
-                                    }
-                                }
-                                entry.insert(info)
+                        let item_stored =
+                            n_stored.entry(&stack.item).or_insert_with(|| factory.get_n_stored(&stack.item));
+                        let mut to_extract = 0;
+                        for (i, output) in this.config.outputs.iter().enumerate() {
+                            if output.item.apply(&stack.item) {
+                                let stored = if output.aggregate { n_stored_all[i] } else { *item_stored };
+                                to_extract = max(to_extract, output.get_n_wanted(factory) - stored)
                             }
-                        };
-                        let to_extract = min(info.n_wanted - info.n_stored, stack.size);
+                        }
+                        let to_extract = min(to_extract, stack.size);
                         if to_extract <= 0 {
                             continue;
                         }
-                        info.n_stored += to_extract;
+                        *item_stored += to_extract;
+                        for (i, output) in this.config.outputs.iter().enumerate() {
+                            if output.aggregate && output.item.apply(&stack.item) {
+                                n_stored_all[i] += to_extract
+                            }
+                        }
                         tasks.push(extract_output(this, factory, slot, to_extract))
                     }
                 }
@@ -1,46 +1,65 @@
-use super::super::access::InvAccess;
-use super::super::action::{ActionFuture, Call};
+use super::super::access::{Access, ItemAccess};
+use super::super::action::ActionFuture;
 use super::super::factory::Factory;
 use super::super::item::{insert_into_inventory, jammer, Filter, InsertPlan, Item, ItemStack};
-use super::super::recipe::{compute_demands, resolve_inputs, Demand, Input, Outputs, Recipe};
+use super::super::recipe::{
+    compute_demands, is_batch_ready, link_recipes, propose_demands, resolve_inputs, Alternative, Demand, DemandPolicy,
+    Input, Outputs, Proposal, Recipe, RecipeLinks,
+};
 use super::super::util::{alive, join_outputs, join_tasks, spawn};
-use super::{extract_output, list_inv, scattering_insert, ExtractFilter, IntoProcess, Inventory, Process, SlotFilter};
+use super::{
+    extract_output, list_inv, n_sets_buffered, record_output, scattering_insert, ExtractFilter, IntoProcess, Inventory,
+    Process, SlotFilter,
+};
 use abort_on_drop::ChildTask;
-use flexstr::{local_str, LocalStr};
+use flexstr::LocalStr;
 use fnv::FnvHashMap;
 use std::{
     cell::RefCell,
     rc::{Rc, Weak},
+    time::Duration,
 };
 
-impl_input!(BufferedInput);
+impl_input!(BufferedInput, mixed);
 #[derive(Clone)]
 pub struct BufferedInput {
     item: Filter,
-    size: i32,
+    size: i64,
     allow_backup: bool,
-    extra_backup: i32,
+    extra_backup: i64,
+    alternatives: Vec<Alternative>,
+    mixed: bool,
 }
 
 impl BufferedInput {
-    pub fn new(item: Filter, size: i32) -> Self { BufferedInput { item, size, allow_backup: false, extra_backup: 0 } }
+    pub fn new(item: Filter, size: i64) -> Self {
+        BufferedInput { item, size, allow_backup: false, extra_backup: 0, alternatives: Vec::new(), mixed: false }
+    }
+
+    pub fn mixed(mut self) -> Self {
+        self.mixed = true;
+        self
+    }
 }
 
-impl_recipe!(BufferedRecipe, BufferedInput);
+impl_recipe!(BufferedRecipe, BufferedInput, batched);
 #[derive(Clone)]
 pub struct BufferedRecipe {
     pub outputs: Rc<dyn Outputs>,
     pub inputs: Vec<BufferedInput>,
-    pub max_inputs: i32,
+    pub max_inputs: i64,
+    pub min_sets: i64,
+    pub max_wait: Option<Duration>,
 }
 
 pub struct BufferedConfig {
     pub name: LocalStr,
-    pub accesses: Vec<InvAccess>,
+    pub accesses: Vec<ItemAccess>,
     pub slot_filter: Option<SlotFilter>,
     pub to_extract: Option<ExtractFilter>,
     pub recipes: Vec<BufferedRecipe>,
-    pub max_recipe_inputs: i32,
+    pub demand_policy: Box<dyn DemandPolicy>,
+    pub max_recipe_inputs: i64,
     pub stocks: Vec<BufferedInput>,
 }
 
@@ -48,6 +67,7 @@ pub struct BufferedProcess {
     weak: Weak<RefCell<BufferedProcess>>,
     config: BufferedConfig,
     factory: Weak<RefCell<Factory>>,
+    listed: Option<Vec<Option<ItemStack>>>,
 }
 
 impl_inventory!(BufferedProcess);
@@ -56,125 +76,238 @@ impl IntoProcess for BufferedConfig {
     type Output = BufferedProcess;
     fn into_process(self, factory: &Factory) -> Rc<RefCell<Self::Output>> {
         Rc::new_cyclic(|weak| {
-            RefCell::new(Self::Output { weak: weak.clone(), config: self, factory: factory.weak.clone() })
+            RefCell::new(Self::Output { weak: weak.clone(), config: self, factory: factory.weak.clone(), listed: None })
         })
     }
 }
 
 impl Process for BufferedProcess {
-    fn run(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+    fn prepare(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
         if self.config.to_extract.is_none() && self.config.stocks.is_empty() {
-            if compute_demands(factory, &self.config.recipes).is_empty() {
-                return spawn(async { Ok(()) });
+            if compute_demands(factory, &self.config.name, &self.config.recipes, &*self.config.demand_policy).is_empty()
+            {
+                // Drops what was listed for a cycle that failed before running.
+                let weak = self.weak.clone();
+                return spawn(async move {
+                    alive_mut!(weak, this);
+                    this.listed = None;
+                    Ok(())
+                });
             }
         }
         let stacks = list_inv(self, factory);
         let weak = self.weak.clone();
         spawn(async move {
-            let mut stacks = stacks.await?;
+            let stacks = stacks.await?;
+            alive_mut!(weak, this);
+            this.listed = Some(stacks);
+            Ok(())
+        })
+    }
+
+    fn run(&self, _factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+        if self.listed.is_none() {
+            return spawn(async { Ok(()) });
+        }
+        let weak = self.weak.clone();
+        spawn(async move {
             let mut tasks = Vec::new();
             {
+                let stacks = alive(&weak)?.borrow_mut().listed.take().unwrap();
                 alive!(weak, this);
                 upgrade_mut!(this.factory, factory);
-                let mut remaining_size = this.config.max_recipe_inputs;
-                let mut existing_size = FnvHashMap::<Rc<Item>, i32>::default();
-                'slot: for (slot, stack) in stacks.iter_mut().enumerate() {
-                    if let Some(ref to_extract) = this.config.to_extract {
-                        if let Some(some_stack) = stack {
-                            if to_extract(factory, slot, some_stack) {
-                                tasks.push(extract_output(this, factory, slot, some_stack.item.max_size));
-                                *stack = Some(ItemStack { item: jammer(), size: 1 });
-                                continue 'slot;
-                            }
-                        }
-                    }
-                    if let Some(ref slot_filter) = this.config.slot_filter {
-                        if !slot_filter(slot) {
-                            *stack = Some(ItemStack { item: jammer(), size: 1 });
-                            continue 'slot;
-                        }
-                    }
-                    if let Some(stack) = stack {
-                        *existing_size.entry(stack.item.clone()).or_default() += stack.size;
-                        for stock in &this.config.stocks {
-                            if stock.item.apply(&stack.item) {
-                                continue 'slot;
-                            }
-                        }
-                        remaining_size -= stack.size;
-                    }
+                let (mut buffer, outputs) = this.scan(factory, stacks);
+                for (slot, stack) in outputs {
+                    let extraction = extract_output(this, factory, slot, stack.item.max_size);
+                    tasks.push(record_output(factory, &this.config.name, &this.config.recipes, &stack, extraction))
                 }
-                for stock in &this.config.stocks {
-                    if let Some((item, info)) = factory.search_item(&stock.item) {
-                        let existing = existing_size.entry(item.clone()).or_default();
-                        let to_insert = (stock.size - *existing)
-                            .min(info.borrow().get_availability(stock.allow_backup, stock.extra_backup));
-                        if to_insert <= 0 {
-                            continue;
-                        }
-                        let InsertPlan { n_inserted, insertions } = insert_into_inventory(&mut stacks, item, to_insert);
-                        if n_inserted <= 0 {
-                            continue;
-                        }
-                        *existing += n_inserted;
-                        let reservation = factory.reserve_item(&this.config.name, item, n_inserted);
-                        tasks.push(scattering_insert(this, factory, reservation, insertions))
-                    }
+                let buffered = buffer
+                    .stacks
+                    .iter()
+                    .flatten()
+                    .filter(|x| !this.config.stocks.iter().any(|y| y.item.apply(&x.item)));
+                let n_sets_buffered = n_sets_buffered(&this.config.recipes, buffered);
+                for (i_recipe, (recipe, n_sets)) in this.config.recipes.iter().zip(n_sets_buffered).enumerate() {
+                    recipe.outputs.add_in_flight(factory, n_sets, None, (&this.config.name, i_recipe))
                 }
-                if remaining_size > 0 {
-                    'recipe: for Demand { i_recipe, .. } in compute_demands(factory, &this.config.recipes) {
-                        let recipe = &this.config.recipes[i_recipe];
-                        if let Some(mut inputs) = resolve_inputs(factory, recipe) {
-                            let size_per_set: i32 = recipe.inputs.iter().map(|x| x.size).sum();
-                            inputs.n_sets = inputs.n_sets.min(remaining_size / size_per_set);
-                            if inputs.n_sets <= 0 {
-                                continue 'recipe;
-                            }
-                            let existing_total: i32 =
-                                inputs.items.iter().map(|item| *existing_size.entry(item.clone()).or_default()).sum();
-                            inputs.n_sets = inputs.n_sets.min((recipe.max_inputs - existing_total) / size_per_set);
-                            if inputs.n_sets <= 0 {
-                                continue 'recipe;
-                            }
-                            let backup = stacks.clone();
-                            let mut plans = Vec::new();
-                            plans.reserve(recipe.inputs.len());
-                            'retry: loop {
-                                for (i_input, item) in inputs.items.iter().enumerate() {
-                                    let to_insert = inputs.n_sets * recipe.inputs[i_input].size;
-                                    let plan = insert_into_inventory(&mut stacks, item, to_insert);
-                                    if plan.n_inserted == to_insert {
-                                        plans.push(plan)
-                                    } else {
-                                        inputs.n_sets -= 1;
-                                        if inputs.n_sets <= 0 {
-                                            continue 'recipe;
-                                        }
-                                        plans.clear();
-                                        stacks = backup.clone();
-                                        continue 'retry;
-                                    }
-                                }
-                                break 'retry;
-                            }
-                            for (i_input, item) in inputs.items.iter().enumerate() {
-                                *existing_size.get_mut(item).unwrap() += plans[i_input].n_inserted
-                            }
-                            remaining_size -= inputs.n_sets * size_per_set;
-                            tasks.push(this.execute_recipe(factory, inputs.items, plans));
-                            if remaining_size <= 0 {
-                                break 'recipe;
-                            }
-                        }
+                for (item, InsertPlan { n_inserted, insertions }) in this.plan_stocks(factory, &mut buffer) {
+                    let reservation = factory.reserve_item(&this.config.name, &item, n_inserted);
+                    tasks.push(scattering_insert(this, factory, reservation, insertions))
+                }
+                for demand in
+                    compute_demands(factory, &this.config.name, &this.config.recipes, &*this.config.demand_policy)
+                {
+                    if buffer.remaining_size <= 0 {
+                        break;
                     }
+                    // Resolved again as earlier recipes have reserved their inputs.
+                    let Some(inputs) = resolve_inputs(factory, &this.config.recipes[demand.i_recipe]) else { continue };
+                    let Some((demand, items, plans)) =
+                        this.plan_recipe(factory, &mut buffer, Demand { inputs, ..demand })
+                    else {
+                        continue;
+                    };
+                    let Demand { i_recipe, inputs, .. } = demand;
+                    this.config.demand_policy.on_executed(i_recipe);
+                    let outputs = &this.config.recipes[i_recipe].outputs;
+                    outputs.add_in_flight(factory, inputs.n_sets, None, (&this.config.name, i_recipe));
+                    factory.record_sets((&this.config.name, i_recipe), inputs.n_sets);
+                    tasks.push(this.execute_recipe(factory, items, plans))
                 }
             }
             join_tasks(tasks).await
         })
     }
+
+    fn propose(&self, factory: &Factory) -> Vec<Proposal> {
+        let Some(ref stacks) = self.listed else { return Vec::new() };
+        let (mut buffer, _) = self.scan(factory, stacks.clone());
+        self.plan_stocks(factory, &mut buffer);
+        let mut demands = Vec::new();
+        for demand in compute_demands(factory, &self.config.name, &self.config.recipes, &*self.config.demand_policy) {
+            if buffer.remaining_size <= 0 {
+                break;
+            }
+            demands.extend(self.plan_recipe(factory, &mut buffer, demand).map(|(demand, _, _)| demand))
+        }
+        propose_demands(&self.config.recipes, demands)
+    }
+
+    fn link_recipes(&self) -> Vec<RecipeLinks> { link_recipes(&self.config.recipes) }
+}
+
+// The buffer as recipes see it: outputs and filtered-out slots are jammed, and stocks don't take up recipe space.
+struct Buffer {
+    stacks: Vec<Option<ItemStack>>,
+    remaining_size: i64,
+    existing_size: FnvHashMap<Rc<Item>, i64>,
 }
 
 impl BufferedProcess {
+    // Also returns the (slot, stack) of outputs to extract.
+    fn scan(&self, factory: &Factory, mut stacks: Vec<Option<ItemStack>>) -> (Buffer, Vec<(usize, ItemStack)>) {
+        let mut outputs = Vec::new();
+        let mut remaining_size = self.config.max_recipe_inputs;
+        let mut existing_size = FnvHashMap::<Rc<Item>, i64>::default();
+        'slot: for (slot, stack) in stacks.iter_mut().enumerate() {
+            if let Some(ref to_extract) = self.config.to_extract {
+                if let Some(some_stack) = stack {
+                    if to_extract(factory, slot, some_stack) {
+                        outputs.push((slot, some_stack.clone()));
+                        *stack = Some(ItemStack { item: jammer(), size: 1 });
+                        continue 'slot;
+                    }
+                }
+            }
+            if let Some(ref slot_filter) = self.config.slot_filter {
+                if !slot_filter(slot) {
+                    *stack = Some(ItemStack { item: jammer(), size: 1 });
+                    continue 'slot;
+                }
+            }
+            if let Some(stack) = stack {
+                *existing_size.entry(stack.item.clone()).or_default() += stack.size;
+                for stock in &self.config.stocks {
+                    if stock.item.apply(&stack.item) {
+                        continue 'slot;
+                    }
+                }
+                remaining_size -= stack.size;
+            }
+        }
+        (Buffer { stacks, remaining_size, existing_size }, outputs)
+    }
+
+    // Tops up stocks, as (item, plan) of each insertion.
+    fn plan_stocks(&self, factory: &Factory, buffer: &mut Buffer) -> Vec<(Rc<Item>, InsertPlan)> {
+        let mut result = Vec::new();
+        // Stocks covered by the same budget share it, as in resolving recipe inputs.
+        let mut budgets_left = FnvHashMap::<usize, i64>::default();
+        for stock in &self.config.stocks {
+            if let Some((item, info)) = factory.search_item(&stock.item) {
+                let existing = buffer.existing_size.entry(item.clone()).or_default();
+                let mut to_insert = (stock.size - *existing)
+                    .min(info.borrow().get_availability(stock.allow_backup, stock.extra_backup));
+                for i_budget in factory.covering_budgets(item) {
+                    to_insert =
+                        to_insert.min(*budgets_left.entry(i_budget).or_insert_with(|| factory.budget_left(i_budget)))
+                }
+                if to_insert <= 0 {
+                    continue;
+                }
+                let plan = insert_into_inventory(&mut buffer.stacks, item, to_insert);
+                if plan.n_inserted <= 0 {
+                    continue;
+                }
+                *existing += plan.n_inserted;
+                for i_budget in factory.covering_budgets(item) {
+                    *budgets_left.get_mut(&i_budget).unwrap() -= plan.n_inserted
+                }
+                result.push((item.clone(), plan))
+            }
+        }
+        result
+    }
+
+    // Clamps a demand to what fits, returning it with the items and plans of its insertions.
+    fn plan_recipe(
+        &self,
+        factory: &Factory,
+        buffer: &mut Buffer,
+        mut demand: Demand,
+    ) -> Option<(Demand, Vec<Rc<Item>>, Vec<InsertPlan>)> {
+        let recipe = &self.config.recipes[demand.i_recipe];
+        let inputs = &mut demand.inputs;
+        // (item, size per set), with mixed inputs split into their parts.
+        let parts = if let Some(ref mixed) = inputs.mixed {
+            Vec::from_iter(mixed.iter().flatten().cloned())
+        } else {
+            Vec::from_iter(inputs.items.iter().cloned().zip(recipe.inputs.iter().map(|x| x.size)))
+        };
+        let size_per_set: i64 = recipe.inputs.iter().map(|x| x.size).sum();
+        inputs.n_sets = inputs.n_sets.min(buffer.remaining_size / size_per_set);
+        if inputs.n_sets <= 0 {
+            return None;
+        }
+        let existing_total: i64 =
+            parts.iter().map(|(item, _)| *buffer.existing_size.entry(item.clone()).or_default()).sum();
+        inputs.n_sets = inputs.n_sets.min((recipe.max_inputs - existing_total) / size_per_set);
+        if inputs.n_sets <= 0 {
+            return None;
+        }
+        let backup = buffer.stacks.clone();
+        let mut plans = Vec::new();
+        plans.reserve(recipe.inputs.len());
+        'retry: loop {
+            for (item, size) in &parts {
+                let to_insert = inputs.n_sets * size;
+                let plan = insert_into_inventory(&mut buffer.stacks, item, to_insert);
+                if plan.n_inserted == to_insert {
+                    plans.push(plan)
+                } else {
+                    inputs.n_sets -= 1;
+                    buffer.stacks = backup.clone();
+                    if inputs.n_sets <= 0 {
+                        return None;
+                    }
+                    plans.clear();
+                    continue 'retry;
+                }
+            }
+            break 'retry;
+        }
+        if !is_batch_ready(factory, recipe, inputs.n_sets) {
+            buffer.stacks = backup;
+            return None;
+        }
+        for ((item, _), plan) in parts.iter().zip(&plans) {
+            *buffer.existing_size.get_mut(item).unwrap() += plan.n_inserted
+        }
+        buffer.remaining_size -= inputs.n_sets * size_per_set;
+        let items = Vec::from_iter(parts.into_iter().map(|(item, _)| item));
+        Some((demand, items, plans))
+    }
+
     fn execute_recipe(
         &self,
         factory: &mut Factory,
@@ -210,22 +343,12 @@ impl BufferedProcess {
                     let mut group = Vec::new();
                     for (i_input, InsertPlan { insertions, .. }) in plans.into_iter().enumerate() {
                         for (inv_slot, size) in insertions {
-                            let action = ActionFuture::from(Call {
-                                addr: access.addr.clone(),
-                                func: local_str!("transferItem"),
-                                args: vec![
-                                    access.bus_side.into(),
-                                    access.inv_side.into(),
-                                    size.into(),
-                                    (bus_slots[i_input] + 1).into(),
-                                    (inv_slot + 1).into(),
-                                ],
-                            });
+                            let action = ActionFuture::from(access.from_bus(size, bus_slots[i_input], Some(inv_slot)));
                             group.push(action.clone().into());
                             tasks.push(spawn(async move { action.await.map(|_| ()) }))
                         }
                     }
-                    server.enqueue_request_group(&access.client, group)
+                    server.enqueue_request_group(access.get_client(), group)
                 }
                 join_tasks(tasks).await?;
                 alive!(weak, this);
@@ -2,7 +2,10 @@ use super::super::access::{Access, CraftingRobotAccess, WorkbenchAccess};
 use super::super::action::{ActionFuture, Call};
 use super::super::factory::Factory;
 use super::super::item::Filter;
-use super::super::recipe::{compute_demands, resolve_inputs, Demand, Input, Outputs, Recipe, ResolvedInputs};
+use super::super::recipe::{
+    compute_demands, is_batch_ready, link_recipes, propose_demands, resolve_inputs, Alternative, Demand, DemandPolicy,
+    Input, Outputs, Proposal, Recipe, RecipeLinks, ResolvedInputs,
+};
 use super::super::side::{DOWN, UP};
 use super::super::util::{alive, join_outputs, join_tasks, spawn};
 use super::{IntoProcess, Process};
@@ -11,21 +14,37 @@ use flexstr::{local_str, LocalStr};
 use std::{
     cell::RefCell,
     rc::{Rc, Weak},
+    time::Duration,
 };
 
 #[derive(Clone)]
 pub struct CraftingGridInput {
     item: Filter,
-    size: i32,
+    size: i64,
     slots: Vec<usize>,
     allow_backup: bool,
-    extra_backup: i32,
+    extra_backup: i64,
+    alternatives: Vec<Alternative>,
+    mixed: bool,
 }
 
-impl_input!(CraftingGridInput);
+impl_input!(CraftingGridInput, mixed);
 impl CraftingGridInput {
     pub fn new(item: Filter, slots: Vec<usize>) -> Self {
-        CraftingGridInput { item, size: slots.len() as i32, slots, allow_backup: false, extra_backup: 0 }
+        CraftingGridInput {
+            item,
+            size: slots.len() as i64,
+            slots,
+            allow_backup: false,
+            extra_backup: 0,
+            alternatives: Vec::new(),
+            mixed: false,
+        }
+    }
+
+    pub fn mixed(mut self) -> Self {
+        self.mixed = true;
+        self
     }
 }
 
@@ -38,7 +57,7 @@ pub struct NonConsumable {
     pub crafting_grid_slot: usize,
 }
 
-impl_recipe!(CraftingGridRecipe, CraftingGridInput);
+impl_recipe!(CraftingGridRecipe, CraftingGridInput, batched);
 #[derive(Clone)]
 pub struct CraftingGridRecipe {
     pub outputs: Rc<dyn Outputs>,
@@ -48,7 +67,9 @@ pub struct CraftingGridRecipe {
     //   6, 7, 8
     pub inputs: Vec<CraftingGridInput>,
     // can't craft more than one stack at a time.
-    pub max_sets: i32,
+    pub max_sets: i64,
+    pub min_sets: i64,
+    pub max_wait: Option<Duration>,
     pub non_consumables: Vec<NonConsumable>,
 }
 
@@ -56,12 +77,13 @@ trait CraftingGridProcess: 'static {
     type Access: Access;
     fn get_accesses(&self) -> &Vec<Self::Access>;
     fn get_recipes(&self) -> &Vec<CraftingGridRecipe>;
+    fn get_demand_policy(&self) -> &dyn DemandPolicy;
     fn get_factory(&self) -> &Weak<RefCell<Factory>>;
     fn get_weak(&self) -> &Weak<RefCell<Self>>;
     fn get_name(&self) -> &str;
-    fn load_input(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, inv_slot: usize, size: i32);
+    fn load_input(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, inv_slot: usize, size: i64);
     fn load_non_consumable(group: &mut Vec<Call>, access: &Self::Access, non_consumable: &NonConsumable);
-    fn store_output(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, n_sets: i32);
+    fn store_output(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, n_sets: i64);
     fn store_non_consumable(group: &mut Vec<Call>, access: &Self::Access, non_consumable: &NonConsumable);
 }
 
@@ -69,28 +91,93 @@ macro_rules! impl_crafting_grid_process {
     () => {
         fn get_accesses(&self) -> &Vec<Self::Access> { &self.config.accesses }
         fn get_recipes(&self) -> &Vec<CraftingGridRecipe> { &self.config.recipes }
+        fn get_demand_policy(&self) -> &dyn DemandPolicy { &*self.config.demand_policy }
         fn get_factory(&self) -> &Weak<RefCell<Factory>> { &self.factory }
         fn get_weak(&self) -> &Weak<RefCell<Self>> { &self.weak }
         fn get_name(&self) -> &str { &self.config.name }
     };
 }
 
-fn run_crafting_grid_process<T>(this: &T, factory: &Factory) -> ChildTask<Result<(), LocalStr>>
+// Sets of a recipe the grid would craft at once, or 0 if it would wait.
+fn clamp_sets(factory: &Factory, recipe: &CraftingGridRecipe, n_sets: i64) -> i64 {
+    let n_sets = n_sets.min(recipe.max_sets);
+    if is_batch_ready(factory, recipe, n_sets) {
+        n_sets
+    } else {
+        0
+    }
+}
+
+fn propose_crafting_grid_demands(this: &impl CraftingGridProcess, factory: &Factory) -> Vec<Proposal> {
+    let mut demands = compute_demands(factory, this.get_name(), this.get_recipes(), this.get_demand_policy());
+    for demand in &mut demands {
+        demand.inputs.n_sets = clamp_sets(factory, &this.get_recipes()[demand.i_recipe], demand.inputs.n_sets)
+    }
+    demands.retain(|x| x.inputs.n_sets > 0);
+    propose_demands(this.get_recipes(), demands)
+}
+
+fn run_crafting_grid_process<T>(this: &T) -> ChildTask<Result<(), LocalStr>>
+where
+    T: CraftingGridProcess,
+{
+    let weak = this.get_weak().clone();
+    spawn(async move {
+        let tasks = {
+            alive!(weak, this);
+            upgrade_mut!(this.get_factory(), factory);
+            plan_crafting_grid_process(this, factory)
+        };
+        join_tasks(tasks).await
+    })
+}
+
+fn plan_crafting_grid_process<T>(this: &T, factory: &mut Factory) -> Vec<ChildTask<Result<(), LocalStr>>>
 where
     T: CraftingGridProcess,
 {
     let mut tasks = Vec::new();
-    for Demand { i_recipe, .. } in compute_demands(factory, this.get_recipes()) {
+    for Demand { i_recipe, .. } in
+        compute_demands(factory, this.get_name(), this.get_recipes(), this.get_demand_policy())
+    {
         let recipe = &this.get_recipes()[i_recipe];
-        if recipe.max_sets <= 0 {
+        // Recipes executed earlier in this loop may have covered the demand.
+        if recipe.max_sets <= 0 || recipe.outputs.get_priority(factory).is_none() {
             continue;
         }
-        if let Some(ResolvedInputs { mut n_sets, items, .. }) = resolve_inputs(factory, recipe) {
-            n_sets = n_sets.min(recipe.max_sets);
+        if let Some(ResolvedInputs { mut n_sets, items, mixed, .. }) = resolve_inputs(factory, recipe) {
+            n_sets = clamp_sets(factory, recipe, n_sets);
+            if n_sets <= 0 {
+                continue;
+            }
+            this.get_demand_policy().on_executed(i_recipe);
+            // Crafted outputs reach storage within this cycle.
+            factory.add_crafted(&*recipe.outputs, n_sets, (this.get_name(), i_recipe));
+            // (item, size) to extract into each bus slot, and (bus slot index, inv slot) to load from.
+            let mut sources = Vec::new();
+            let mut loads = Vec::new();
+            if let Some(mixed) = mixed {
+                for (input, parts) in recipe.inputs.iter().zip(mixed) {
+                    let mut inv_slots = input.slots.iter();
+                    for (item, size) in parts {
+                        for inv_slot in inv_slots.by_ref().take(size as usize) {
+                            loads.push((sources.len(), *inv_slot))
+                        }
+                        sources.push((item, size))
+                    }
+                }
+            } else {
+                for (input, item) in recipe.inputs.iter().zip(items) {
+                    for inv_slot in &input.slots {
+                        loads.push((sources.len(), *inv_slot))
+                    }
+                    sources.push((item, n_sets * input.size))
+                }
+            }
             let mut bus_slots = Vec::new();
             let slots_to_free = Rc::new(RefCell::new(Vec::new()));
-            for (i_input, item) in items.into_iter().enumerate() {
-                let reservation = factory.reserve_item(this.get_name(), &item, n_sets * recipe.inputs[i_input].size);
+            for (item, size) in sources {
+                let reservation = factory.reserve_item(this.get_name(), &item, size);
                 let slots_to_free = slots_to_free.clone();
                 let weak = this.get_factory().clone();
                 bus_slots.push(spawn(async move {
@@ -124,10 +211,8 @@ where
                         let access = server.load_balance(this.get_accesses()).1;
                         let mut group = Vec::new();
                         let recipe = &this.get_recipes()[i_recipe];
-                        for (i_input, input) in recipe.inputs.iter().enumerate() {
-                            for inv_slot in &input.slots {
-                                T::load_input(&mut group, access, bus_slots[i_input], *inv_slot, n_sets)
-                            }
+                        for (i_source, inv_slot) in loads {
+                            T::load_input(&mut group, access, bus_slots[i_source], inv_slot, n_sets)
                         }
                         for non_consumable in &recipe.non_consumables {
                             T::load_non_consumable(&mut group, access, non_consumable)
@@ -159,13 +244,14 @@ where
             }))
         }
     }
-    spawn(async move { join_tasks(tasks).await })
+    tasks
 }
 
 pub struct CraftingRobotConfig {
     pub name: LocalStr,
     pub accesses: Vec<CraftingRobotAccess>,
     pub recipes: Vec<CraftingGridRecipe>,
+    pub demand_policy: Box<dyn DemandPolicy>,
 }
 
 pub struct CraftingRobotProcess {
@@ -197,7 +283,7 @@ impl CraftingGridProcess for CraftingRobotProcess {
     type Access = CraftingRobotAccess;
     impl_crafting_grid_process!();
 
-    fn load_input(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, inv_slot: usize, size: i32) {
+    fn load_input(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, inv_slot: usize, size: i64) {
         group.push(Call {
             addr: local_str!("robot"),
             func: local_str!("select"),
@@ -223,7 +309,7 @@ impl CraftingGridProcess for CraftingRobotProcess {
         })
     }
 
-    fn store_output(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, _n_sets: i32) {
+    fn store_output(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, _n_sets: i64) {
         group.push(Call { addr: local_str!("robot"), func: local_str!("select"), args: vec![16.into()] });
         group.push(Call { addr: local_str!("crafting"), func: local_str!("craft"), args: Vec::new() });
         group.push(Call {
@@ -248,13 +334,16 @@ impl CraftingGridProcess for CraftingRobotProcess {
 }
 
 impl Process for CraftingRobotProcess {
-    fn run(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> { run_crafting_grid_process(self, factory) }
+    fn run(&self, _factory: &Factory) -> ChildTask<Result<(), LocalStr>> { run_crafting_grid_process(self) }
+    fn propose(&self, factory: &Factory) -> Vec<Proposal> { propose_crafting_grid_demands(self, factory) }
+    fn link_recipes(&self) -> Vec<RecipeLinks> { link_recipes(&self.config.recipes) }
 }
 
 pub struct WorkbenchConfig {
     pub name: LocalStr,
     pub accesses: Vec<WorkbenchAccess>,
     pub recipes: Vec<CraftingGridRecipe>,
+    pub demand_policy: Box<dyn DemandPolicy>,
 }
 
 pub struct WorkbenchProcess {
@@ -276,7 +365,7 @@ impl CraftingGridProcess for WorkbenchProcess {
     type Access = WorkbenchAccess;
     impl_crafting_grid_process!();
 
-    fn load_input(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, inv_slot: usize, size: i32) {
+    fn load_input(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, inv_slot: usize, size: i64) {
         group.push(Call {
             addr: access.input_addr.clone(),
             func: local_str!("transferItem"),
@@ -304,7 +393,7 @@ impl CraftingGridProcess for WorkbenchProcess {
         })
     }
 
-    fn store_output(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, n_sets: i32) {
+    fn store_output(group: &mut Vec<Call>, access: &Self::Access, bus_slot: usize, n_sets: i64) {
         for _ in 0..n_sets {
             group.push(Call {
                 addr: access.output_addr.clone(),
@@ -330,5 +419,7 @@ impl CraftingGridProcess for WorkbenchProcess {
 }
 
 impl Process for WorkbenchProcess {
-    fn run(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> { run_crafting_grid_process(self, factory) }
+    fn run(&self, _factory: &Factory) -> ChildTask<Result<(), LocalStr>> { run_crafting_grid_process(self) }
+    fn propose(&self, factory: &Factory) -> Vec<Proposal> { propose_crafting_grid_demands(self, factory) }
+    fn link_recipes(&self) -> Vec<RecipeLinks> { link_recipes(&self.config.recipes) }
 }
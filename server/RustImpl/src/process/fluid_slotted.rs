@@ -1,11 +1,15 @@
 use super::{
-    extract_output, list_inv, EachInv, EachInvConfig, IntoProcess, MultiInvExtractFilter, MultiInvSlottedInput, Process,
+    extract_output, list_inv, n_sets_loaded, record_output, EachInv, EachInvConfig, IntoProcess, MultiInvExtractFilter,
+    MultiInvSlottedInput, Process,
 };
 use crate::access::{EachTank, InvAccess, InvTankAccess};
 use crate::action::{ActionFuture, Call};
 use crate::factory::{read_tanks, tanks_to_fluid_map, Factory, Tank};
 use crate::item::ItemStack;
-use crate::recipe::{resolve_inputs, Demand, Outputs, Recipe};
+use crate::recipe::{
+    is_batch_ready, link_recipes, propose_demands, resolve_inputs_unbatched, Demand, DemandPolicy, Outputs, Proposal,
+    Recipe, RecipeLinks,
+};
 use crate::util::{alive, join_outputs, join_tasks, spawn};
 use abort_on_drop::ChildTask;
 use flexstr::{local_str, LocalStr};
@@ -14,6 +18,7 @@ use std::collections::hash_map::Entry;
 use std::{
     cell::RefCell,
     rc::{Rc, Weak},
+    time::Duration,
 };
 
 #[derive(Clone)]
@@ -42,13 +47,15 @@ impl FluidSlottedInput {
     }
 }
 
-impl_recipe!(FluidSlottedRecipe, MultiInvSlottedInput);
+impl_recipe!(FluidSlottedRecipe, MultiInvSlottedInput, batched);
 #[derive(Clone)]
 pub struct FluidSlottedRecipe {
     pub outputs: Rc<dyn Outputs>,
     pub inputs: Vec<MultiInvSlottedInput>,
     pub fluids: Vec<FluidSlottedInput>,
-    pub max_sets: i32,
+    pub max_sets: i64,
+    pub min_sets: i64,
+    pub max_wait: Option<Duration>,
 }
 
 pub type FluidExtractFilter = Box<dyn Fn(&Factory, usize, Vec<Tank>) -> FnvHashMap<LocalStr, (usize, i64)>>;
@@ -75,6 +82,7 @@ pub struct FluidSlottedConfig {
     pub fluid_extract: Option<FluidExtractFilter>,
     pub recipes: Vec<FluidSlottedRecipe>,
     pub strict_priority: bool,
+    pub demand_policy: Box<dyn DemandPolicy>,
 }
 
 pub struct FluidSlottedProcess {
@@ -86,10 +94,15 @@ pub struct FluidSlottedProcess {
     fluid_extract: Option<FluidExtractFilter>,
     recipes: Vec<FluidSlottedRecipe>,
     strict_priority: bool,
+    demand_policy: Box<dyn DemandPolicy>,
     invs: Vec<Rc<RefCell<EachInv>>>,
     input_tanks: Vec<Vec<usize>>,
+    listed: Option<Listed>,
 }
 
+// Stacks and tanks of each inventory, as listed before arbitration.
+type Listed = (Vec<Vec<Option<ItemStack>>>, Vec<Vec<Tank>>);
+
 impl IntoProcess for FluidSlottedConfig {
     type Output = FluidSlottedProcess;
     fn into_process(self, factory: &Factory) -> Rc<RefCell<Self::Output>> {
@@ -108,6 +121,7 @@ impl IntoProcess for FluidSlottedConfig {
                                     bus_side: each.bus_side,
                                     inv_side: each.inv_side,
                                 }
+                                .into()
                             })),
                             input_slots,
                         },
@@ -124,8 +138,10 @@ impl IntoProcess for FluidSlottedConfig {
                 fluid_extract: self.fluid_extract,
                 recipes: self.recipes,
                 strict_priority: self.strict_priority,
+                demand_policy: self.demand_policy,
                 invs,
                 input_tanks: self.input_tanks,
+                listed: None,
             })
         })
     }
@@ -136,11 +152,16 @@ struct InputInfo {
     n_needed: i64,
 }
 
-fn compute_fluid_demands(factory: &Factory, recipes: &[FluidSlottedRecipe]) -> Vec<Demand> {
+fn compute_fluid_demands(
+    factory: &Factory,
+    name: &str,
+    recipes: &[FluidSlottedRecipe],
+    policy: &dyn DemandPolicy,
+) -> Vec<Demand> {
     let mut result = Vec::new();
     for (i_recipe, recipe) in recipes.iter().enumerate() {
         let Some(mut priority) = recipe.get_outputs().get_priority(factory) else { continue };
-        let Some(mut inputs) = resolve_inputs(factory, recipe) else { continue };
+        let Some(mut inputs) = resolve_inputs_unbatched(factory, recipe) else { continue };
         let mut infos = FnvHashMap::<LocalStr, InputInfo>::default();
         let mut bus_bound = i64::MAX;
         for input in &recipe.fluids {
@@ -164,24 +185,30 @@ fn compute_fluid_demands(factory: &Factory, recipes: &[FluidSlottedRecipe]) -> V
         for (_, input_info) in infos {
             availability_bound = availability_bound.min(input_info.n_available / input_info.n_needed)
         }
-        inputs.n_sets = (inputs.n_sets as i64).min(bus_bound).min(availability_bound) as _;
-        if inputs.n_sets > 0 {
-            inputs.priority = (inputs.priority as i64).min(availability_bound) as _;
-            priority *= inputs.priority as f64;
+        inputs.n_sets = inputs.n_sets.min(bus_bound).min(availability_bound);
+        if is_batch_ready(factory, recipe, inputs.n_sets) {
+            inputs.priority = inputs.priority.min(availability_bound);
+            priority *= inputs.priority as f64 * recipe.get_outputs().get_per_set(factory, None, (name, i_recipe));
             result.push(Demand { i_recipe, inputs, priority })
         }
     }
-    result.sort_by(|x: &Demand, y: &Demand| x.priority.partial_cmp(&y.priority).unwrap().reverse());
+    policy.order(&mut result);
     result
 }
 
 impl Process for FluidSlottedProcess {
-    fn run(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+    fn prepare(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
         if self.to_extract.is_none()
             && self.fluid_extract.is_none()
-            && compute_fluid_demands(factory, &self.recipes).is_empty()
+            && compute_fluid_demands(factory, &self.name, &self.recipes, &*self.demand_policy).is_empty()
         {
-            return spawn(async { Ok(()) });
+            // Drops what was listed for a cycle that failed before running.
+            let weak = self.weak.clone();
+            return spawn(async move {
+                alive_mut!(weak, this);
+                this.listed = None;
+                Ok(())
+            });
         }
         let stacks = Vec::from_iter(self.invs.iter().map(|inv| spawn(list_inv(&*inv.borrow(), factory))));
         let tanks = Vec::from_iter((0..self.input_tanks.len()).map(|i| {
@@ -194,110 +221,166 @@ impl Process for FluidSlottedProcess {
         spawn(async move {
             let stacks = join_outputs(stacks).await?;
             let tanks = join_outputs(tanks).await?;
+            alive_mut!(weak, this);
+            this.listed = Some((stacks, tanks));
+            Ok(())
+        })
+    }
+
+    fn run(&self, _factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+        if self.listed.is_none() {
+            return spawn(async { Ok(()) });
+        }
+        let weak = self.weak.clone();
+        spawn(async move {
             let mut tasks = Vec::new();
             {
+                let (stacks, tanks) = alive(&weak)?.borrow_mut().listed.take().unwrap();
                 alive!(weak, this);
                 upgrade_mut!(this.factory, factory);
-                let mut existing_inputs = FnvHashMap::<(usize, usize), Option<ItemStack>>::default();
-                for (i, inv) in this.invs.iter().enumerate() {
-                    for slot in &inv.borrow().config.input_slots {
-                        existing_inputs.insert((i, *slot), None);
-                    }
-                }
+                let existing_inputs = this.existing_inputs(&stacks);
                 for (i, stacks) in stacks.into_iter().enumerate() {
                     for (slot, stack) in stacks.into_iter().enumerate() {
                         if let Some(stack) = stack {
-                            if let Some(existing_input) = existing_inputs.get_mut(&(i, slot)) {
-                                *existing_input = Some(stack)
+                            if existing_inputs.contains_key(&(i, slot)) {
+                                continue;
                             } else if let Some(to_extract) = &this.to_extract {
                                 if to_extract(factory, i, slot, &stack) {
-                                    tasks.push(extract_output(
-                                        &*this.invs[i].borrow(),
-                                        factory,
-                                        slot,
-                                        stack.item.max_size,
-                                    ))
+                                    let extraction =
+                                        extract_output(&*this.invs[i].borrow(), factory, slot, stack.item.max_size);
+                                    tasks.push(record_output(factory, &this.name, &this.recipes, &stack, extraction))
                                 }
                             }
                         }
                     }
                 }
-                let existing_fluids = Vec::from_iter(tanks.into_iter().enumerate().map(|(i, tanks)| {
-                    let mut fluid_map = FnvHashMap::<LocalStr, i64>::default();
-                    for &slot in &this.input_tanks[i] {
-                        let tank = &tanks[slot];
-                        let Some(fluid) = &tank.fluid else { continue };
-                        *fluid_map.entry(fluid.clone()).or_default() += tank.qty
-                    }
-                    if let Some(fluid_extract) = &this.fluid_extract {
+                let existing_fluids = this.existing_fluids(&tanks);
+                if let Some(fluid_extract) = &this.fluid_extract {
+                    for (i, tanks) in tanks.into_iter().enumerate() {
                         this.extract_fluids(factory, i, fluid_extract(factory, i, tanks), &mut tasks)
                     }
-                    fluid_map
-                }));
-                let mut demands = compute_fluid_demands(factory, &this.recipes);
-                if this.strict_priority {
-                    demands.truncate(1)
                 }
-                'recipe: for mut demand in demands.into_iter() {
-                    let recipe = &this.recipes[demand.i_recipe];
-                    let mut used_slots = FnvHashSet::<(usize, usize)>::default();
-                    for (i_input, input) in recipe.inputs.iter().enumerate() {
-                        for (inv, inv_slot, mult) in &input.slots {
-                            let slot = (*inv, *inv_slot);
-                            let existing_input = existing_inputs.get(&slot).unwrap();
-                            let existing_size = if let Some(existing_input) = existing_input {
-                                if existing_input.item != demand.inputs.items[i_input] {
-                                    continue 'recipe;
-                                }
-                                existing_input.size
-                            } else {
-                                0
-                            };
-                            demand.inputs.n_sets = demand.inputs.n_sets.min(
-                                ((recipe.max_sets * mult).min(demand.inputs.items[i_input].max_size) - existing_size)
-                                    / mult,
-                            );
-                            if demand.inputs.n_sets <= 0 {
-                                continue 'recipe;
-                            }
-                            used_slots.insert(slot);
-                        }
-                    }
-                    for (slot, existing_input) in &existing_inputs {
-                        if existing_input.is_some() && !used_slots.contains(slot) {
+                for (i_recipe, recipe) in this.recipes.iter().enumerate() {
+                    let slots = recipe
+                        .inputs
+                        .iter()
+                        .flat_map(|x| x.slots.iter().map(move |&(inv, slot, mult)| (x, (inv, slot), mult)));
+                    recipe.outputs.add_in_flight(
+                        factory,
+                        n_sets_loaded(&existing_inputs, slots),
+                        None,
+                        (&this.name, i_recipe),
+                    )
+                }
+                if let Some(demand) = this.plan(factory, &existing_inputs, &existing_fluids) {
+                    this.demand_policy.on_executed(demand.i_recipe);
+                    let outputs = &this.recipes[demand.i_recipe].outputs;
+                    outputs.add_in_flight(factory, demand.inputs.n_sets, None, (&this.name, demand.i_recipe));
+                    factory.record_sets((&this.name, demand.i_recipe), demand.inputs.n_sets);
+                    tasks.push(this.execute_recipe(factory, demand))
+                }
+            }
+            join_tasks(tasks).await
+        })
+    }
+
+    fn propose(&self, factory: &Factory) -> Vec<Proposal> {
+        let Some((ref stacks, ref tanks)) = self.listed else { return Vec::new() };
+        let demand = self.plan(factory, &self.existing_inputs(stacks), &self.existing_fluids(tanks));
+        propose_demands(&self.recipes, Vec::from_iter(demand))
+    }
+
+    fn link_recipes(&self) -> Vec<RecipeLinks> { link_recipes(&self.recipes) }
+}
+
+impl FluidSlottedProcess {
+    fn existing_inputs(&self, stacks: &[Vec<Option<ItemStack>>]) -> FnvHashMap<(usize, usize), Option<ItemStack>> {
+        let mut result = FnvHashMap::default();
+        for (i, inv) in self.invs.iter().enumerate() {
+            for &slot in &inv.borrow().config.input_slots {
+                result.insert((i, slot), stacks[i].get(slot).cloned().flatten());
+            }
+        }
+        result
+    }
+
+    fn existing_fluids(&self, tanks: &[Vec<Tank>]) -> Vec<FnvHashMap<LocalStr, i64>> {
+        Vec::from_iter(tanks.iter().zip(&self.input_tanks).map(|(tanks, input_tanks)| {
+            let mut fluid_map = FnvHashMap::<LocalStr, i64>::default();
+            for &slot in input_tanks {
+                let tank = &tanks[slot];
+                let Some(fluid) = &tank.fluid else { continue };
+                *fluid_map.entry(fluid.clone()).or_default() += tank.qty
+            }
+            fluid_map
+        }))
+    }
+
+    // The demand to load given what's in the input slots and tanks, clamped to what fits.
+    fn plan(
+        &self,
+        factory: &Factory,
+        existing_inputs: &FnvHashMap<(usize, usize), Option<ItemStack>>,
+        existing_fluids: &[FnvHashMap<LocalStr, i64>],
+    ) -> Option<Demand> {
+        let mut demands = compute_fluid_demands(factory, &self.name, &self.recipes, &*self.demand_policy);
+        if self.strict_priority {
+            demands.truncate(1)
+        }
+        'recipe: for mut demand in demands.into_iter() {
+            let recipe = &self.recipes[demand.i_recipe];
+            let mut used_slots = FnvHashSet::<(usize, usize)>::default();
+            for (i_input, input) in recipe.inputs.iter().enumerate() {
+                for (inv, inv_slot, mult) in &input.slots {
+                    let slot = (*inv, *inv_slot);
+                    let existing_input = existing_inputs.get(&slot).unwrap();
+                    let existing_size = if let Some(existing_input) = existing_input {
+                        if existing_input.item != demand.inputs.items[i_input] {
                             continue 'recipe;
                         }
-                    }
-                    let mut mismatched_fluids = FnvHashSet::from_iter(
-                        (existing_fluids.iter().enumerate())
-                            .flat_map(|(i, fluid_map)| fluid_map.keys().map(move |fluid| (i, fluid.clone()))),
+                        existing_input.size
+                    } else {
+                        0
+                    };
+                    demand.inputs.n_sets = demand.inputs.n_sets.min(
+                        (recipe.max_sets.saturating_mul(*mult).min(demand.inputs.items[i_input].max_size)
+                            - existing_size)
+                            / mult,
                     );
-                    for input in &recipe.fluids {
-                        for &(i, mult) in &input.tanks {
-                            mismatched_fluids.remove(&(i, input.fluid.clone()));
-                            let fluid_map = &existing_fluids[i];
-                            let existing_size = fluid_map.get(&input.fluid).copied().unwrap_or_default();
-                            demand.inputs.n_sets = (demand.inputs.n_sets)
-                                .min(((recipe.max_sets as i64 * mult - existing_size) / mult).clamp(0, i32::MAX as _)
-                                    as _);
-                            if demand.inputs.n_sets <= 0 {
-                                continue 'recipe;
-                            }
-                        }
+                    if demand.inputs.n_sets <= 0 {
+                        continue 'recipe;
                     }
-                    if !mismatched_fluids.is_empty() {
-                        continue;
+                    used_slots.insert(slot);
+                }
+            }
+            for (slot, existing_input) in existing_inputs {
+                if existing_input.is_some() && !used_slots.contains(slot) {
+                    continue 'recipe;
+                }
+            }
+            let mut mismatched_fluids = FnvHashSet::from_iter(
+                (existing_fluids.iter().enumerate())
+                    .flat_map(|(i, fluid_map)| fluid_map.keys().map(move |fluid| (i, fluid.clone()))),
+            );
+            for input in &recipe.fluids {
+                for &(i, mult) in &input.tanks {
+                    mismatched_fluids.remove(&(i, input.fluid.clone()));
+                    let fluid_map = &existing_fluids[i];
+                    let existing_size = fluid_map.get(&input.fluid).copied().unwrap_or_default();
+                    demand.inputs.n_sets =
+                        demand.inputs.n_sets.min((recipe.max_sets.saturating_mul(mult) - existing_size).max(0) / mult);
+                    if demand.inputs.n_sets <= 0 {
+                        continue 'recipe;
                     }
-                    tasks.push(this.execute_recipe(factory, demand));
-                    break;
                 }
             }
-            join_tasks(tasks).await
-        })
+            if mismatched_fluids.is_empty() && is_batch_ready(factory, recipe, demand.inputs.n_sets) {
+                return Some(demand);
+            }
+        }
+        None
     }
-}
 
-impl FluidSlottedProcess {
     fn extract_fluids(
         &self,
         factory: &mut Factory,
@@ -356,8 +439,7 @@ impl FluidSlottedProcess {
             }))
         }
         for input in &recipe.fluids {
-            let reservation =
-                factory.reserve_fluid(&self.name, &*input.fluid, input.size * demand.inputs.n_sets as i64);
+            let reservation = factory.reserve_fluid(&self.name, &*input.fluid, input.size * demand.inputs.n_sets);
             let fluid_bus = factory.fluid_bus_allocate();
             let fluid_buses_to_free = fluid_buses_to_free.clone();
             fluid_buses.push(spawn(async move {
@@ -411,7 +493,7 @@ impl FluidSlottedProcess {
                                 args: vec![
                                     bus_of_tank.bus_side.into(),
                                     bus_of_tank.tank_side.into(),
-                                    (demand.inputs.n_sets as i64 * mult).into(),
+                                    (demand.inputs.n_sets * mult).into(),
                                 ],
                             });
                             group.push(action.clone().into());
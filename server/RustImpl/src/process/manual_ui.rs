@@ -1,6 +1,6 @@
 use super::{list_inv, scattering_insert, IntoProcess, Inventory, Process};
-use crate::access::InvAccess;
-use crate::item::{insert_into_inventory, InsertPlan, ItemStack};
+use crate::access::ItemAccess;
+use crate::item::{insert_into_inventory, FilterExpr, InsertPlan, ItemStack};
 use crate::util::{alive, join_tasks, spawn};
 use crate::{factory::Factory, Tui};
 use abort_on_drop::ChildTask;
@@ -11,11 +11,12 @@ use ratatui::text::{Line, Span};
 use regex::Regex;
 use std::{
     cell::RefCell,
+    mem::take,
     rc::{Rc, Weak},
 };
 
 pub struct ManualUiConfig {
-    pub accesses: Vec<InvAccess>,
+    pub accesses: Vec<ItemAccess>,
 }
 
 impl_inventory!(ManualUiProcess);
@@ -53,12 +54,12 @@ async fn input_handler(tui: Rc<Tui>, weak: Weak<RefCell<ManualUiProcess>>) {
     }
 }
 
+// The needle is a filter expression, or a label regex if it doesn't parse as one.
 fn make_pred(needle: &str) -> Box<dyn Fn(&ItemStack) -> bool> {
-    if needle.is_empty() {
+    if needle.trim().is_empty() {
         Box::new(|_| true)
-    } else if needle.starts_with("=") {
-        let Ok(regex) = Regex::new(&needle[1..]) else { return Box::new(|_| false) };
-        Box::new(move |x| regex.is_match(&x.item.name))
+    } else if let Ok(expr) = needle.parse::<FilterExpr>() {
+        Box::new(move |x| expr.apply(&x.item))
     } else {
         let Ok(regex) = Regex::new(needle) else { return Box::new(|_| false) };
         Box::new(move |x| regex.is_match(&x.item.label))
@@ -105,7 +106,13 @@ impl Process for ManualUiProcess {
                 this.latest_view.sort_by_key(|x| -x.size);
                 let tui = factory.config.tui.clone();
                 this.update_view(&tui);
-                for request in tui.input_queue.borrow_mut().drain(..) {
+                // Commands are left for the factory to handle.
+                let mut input_queue = tui.input_queue.borrow_mut();
+                let (commands, requests): (Vec<_>, _) =
+                    take(&mut *input_queue).into_iter().partition(|x| x.starts_with('/'));
+                *input_queue = commands;
+                drop(input_queue);
+                for request in requests {
                     let Some(pos) = request.rfind('*') else { continue };
                     let pred = make_pred(&request[..pos]);
                     let Some(stack) = this.latest_view.iter().find(|x| pred(x)) else { continue };
@@ -1,4 +1,4 @@
-use super::super::access::{ComponentAccess, InvAccess, SidedAccess};
+use super::super::access::{Access, ComponentAccess, ItemAccess, SidedAccess};
 use super::super::action::{ActionFuture, Call, Print};
 use super::super::factory::Factory;
 use super::super::item::Filter;
@@ -48,7 +48,7 @@ impl<T: Process> Process for ConditionalProcess<T> {
 pub struct PlasticMixerConfig {
     pub name: LocalStr,
     pub accesses: Vec<ComponentAccess>,
-    pub n_wanted: i32,
+    pub n_wanted: i64,
 }
 
 pub struct PlasticMixerProcess {
@@ -179,20 +179,11 @@ impl Process for FluxNetworkProcess {
 
 pub struct LowAlert {
     item: Filter,
-    n_wanted: i32,
-    log: LocalStr,
+    n_wanted: i64,
 }
 
 impl LowAlert {
-    pub fn new(item: Filter, n_wanted: i32) -> Self {
-        let log = match &item {
-            Filter::Label(x) => x.clone(),
-            Filter::Name(x) => local_fmt!("<{}>", x),
-            Filter::Both { label, name } => local_fmt!("{} <{}>", label, name),
-            Filter::Custom { desc, .. } => local_fmt!("<{}>", desc),
-        };
-        Self { item, n_wanted, log }
-    }
+    pub fn new(item: Filter, n_wanted: i64) -> Self { Self { item, n_wanted } }
 }
 
 impl Process for LowAlert {
@@ -200,7 +191,7 @@ impl Process for LowAlert {
         let n_stored = factory.search_n_stored(&self.item);
         if n_stored < self.n_wanted {
             factory.log(Print {
-                text: local_fmt!("need {}*{}", self.log, self.n_wanted - n_stored),
+                text: local_fmt!("need {}*{}", self.item, self.n_wanted - n_stored),
                 color: 0xF2B2CC,
                 beep: None,
             })
@@ -227,7 +218,7 @@ impl Process for FluidLowAlert {
 pub struct ItemCycleConfig {
     pub name: LocalStr,
     pub file_name: LocalStr,
-    pub accesses: Vec<InvAccess>,
+    pub accesses: Vec<ItemAccess>,
     pub slot: usize,
     pub items: Vec<ScatteringInput>,
 }
@@ -288,18 +279,8 @@ impl Process for ItemCycleProcess {
                             upgrade!(this.factory, factory);
                             let server = factory.borrow_server();
                             let access = server.load_balance(&this.config.accesses).1;
-                            let action = ActionFuture::from(Call {
-                                addr: access.addr.clone(),
-                                func: local_str!("transferItem"),
-                                args: vec![
-                                    access.bus_side.into(),
-                                    access.inv_side.into(),
-                                    1.into(),
-                                    (bus_slot + 1).into(),
-                                    (this.config.slot + 1).into(),
-                                ],
-                            });
-                            server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+                            let action = ActionFuture::from(access.from_bus(1, bus_slot, Some(this.config.slot)));
+                            server.enqueue_request_group(access.get_client(), vec![action.clone().into()]);
                             action
                         };
                         task.await?;
@@ -1,18 +1,27 @@
-use super::access::InvAccess;
-use super::action::{ActionFuture, Call, List};
+use super::access::{Access, ItemAccess};
+use super::action::ActionFuture;
 use super::factory::{Factory, Reservation};
 use super::item::ItemStack;
+use super::recipe::{produces, Input, Proposal, Recipe, RecipeLinks};
 use super::util::{alive, join_tasks, spawn};
 use abort_on_drop::ChildTask;
-use flexstr::{local_str, LocalStr};
+use flexstr::LocalStr;
+use fnv::FnvHashMap;
 use std::{
     cell::RefCell,
+    future::Future,
+    hash::Hash,
     iter::once,
     rc::{Rc, Weak},
 };
 
 pub trait Process: 'static {
+    // Lists the machine before arbitration so that `propose` and `run` plan the same loads.
+    fn prepare(&self, _factory: &Factory) -> ChildTask<Result<(), LocalStr>> { spawn(async { Ok(()) }) }
     fn run(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>>;
+    // Called before any process runs so that scarce items go to the most urgent recipes.
+    fn propose(&self, _factory: &Factory) -> Vec<Proposal> { Vec::new() }
+    fn link_recipes(&self) -> Vec<RecipeLinks> { Vec::new() }
 }
 
 pub trait IntoProcess {
@@ -25,12 +34,83 @@ impl<T: Process> IntoProcess for T {
     fn into_process(self, _: &Factory) -> Rc<RefCell<Self::Output>> { Rc::new(RefCell::new(self)) }
 }
 
+// Sets of a recipe sitting in a machine's input slots, given (input, slot, size per set) of each input slot.
+pub fn n_sets_loaded<'a, K: Eq + Hash + 'a, I: Input + 'a>(
+    existing_inputs: &FnvHashMap<K, Option<ItemStack>>,
+    slots: impl IntoIterator<Item = (&'a I, K, i64)>,
+) -> i64 {
+    let mut result = i64::MAX;
+    for (input, slot, mult) in slots {
+        let Some(Some(stack)) = existing_inputs.get(&slot) else { return 0 };
+        if !input.accepts(&stack.item) {
+            return 0;
+        }
+        result = result.min(stack.size / mult)
+    }
+    if result == i64::MAX {
+        0
+    } else {
+        result
+    }
+}
+
+// Sets of each recipe sitting in a buffer, counting each stack toward the first recipe input that accepts it.
+pub fn n_sets_buffered<'a>(recipes: &[impl Recipe], stacks: impl IntoIterator<Item = &'a ItemStack>) -> Vec<i64> {
+    let mut totals = Vec::from_iter(recipes.iter().map(|recipe| vec![0; recipe.get_inputs().len()]));
+    'stack: for stack in stacks {
+        for (recipe, totals) in recipes.iter().zip(&mut totals) {
+            for (input, total) in recipe.get_inputs().iter().zip(totals) {
+                if input.accepts(&stack.item) {
+                    *total += stack.size;
+                    continue 'stack;
+                }
+            }
+        }
+    }
+    Vec::from_iter(recipes.iter().zip(totals).map(|(recipe, totals)| {
+        recipe.get_inputs().iter().zip(totals).map(|(input, total)| total / input.get_size()).min().unwrap_or(0)
+    }))
+}
+
+// Credits extracted items to each output of the recipes producing them once `extraction` succeeds, for learning yields.
+// When several recipes produce the item, it's split by the sets each executed.
+pub fn record_output(
+    factory: &Factory,
+    name: &str,
+    recipes: &[impl Recipe],
+    stack: &ItemStack,
+    extraction: ChildTask<Result<(), LocalStr>>,
+) -> ChildTask<Result<(), LocalStr>> {
+    let producers = Vec::from_iter((0..recipes.len()).filter(|&i| produces(&recipes[i], &stack.item)));
+    let weights = Vec::from_iter(producers.iter().map(|&i| factory.get_n_sets((name, i)) as f64));
+    let total: f64 = weights.iter().sum();
+    let mut credits = Vec::new();
+    for (&i_recipe, weight) in producers.iter().zip(weights) {
+        let share = if total > 0. { weight / total } else { 1. / producers.len() as f64 };
+        recipes[i_recipe].get_outputs().for_each_filter(&mut |x| {
+            if x.apply(&stack.item) {
+                credits.push((i_recipe, x.clone(), stack.size as f64 * share))
+            }
+        });
+    }
+    let name = LocalStr::from_ref(name);
+    let weak = factory.weak.clone();
+    spawn(async move {
+        extraction.await.unwrap()?;
+        alive_mut!(weak, factory);
+        for (i_recipe, filter, n_produced) in credits {
+            factory.record_produced((&name, i_recipe), &filter, n_produced)
+        }
+        Ok(())
+    })
+}
+
 pub type SlotFilter = Box<dyn Fn(usize) -> bool>;
 pub type ExtractFilter = Box<dyn Fn(&Factory, usize, &ItemStack) -> bool>;
 pub fn extract_all() -> Option<ExtractFilter> { Some(Box::new(|_, _, _| true)) }
 
 pub trait Inventory: 'static {
-    fn get_accesses(&self) -> &Vec<InvAccess>;
+    fn get_accesses(&self) -> &Vec<ItemAccess>;
     fn get_weak(&self) -> &Weak<RefCell<Self>>;
     fn get_factory(&self) -> &Weak<RefCell<Factory>>;
 }
@@ -38,25 +118,31 @@ pub trait Inventory: 'static {
 macro_rules! impl_inventory {
     ($i:ident) => {
         impl Inventory for $i {
-            fn get_accesses(&self) -> &Vec<InvAccess> { &self.config.accesses }
+            fn get_accesses(&self) -> &Vec<ItemAccess> { &self.config.accesses }
             fn get_weak(&self) -> &Weak<RefCell<Self>> { &self.weak }
             fn get_factory(&self) -> &Weak<RefCell<Factory>> { &self.factory }
         }
     };
 }
 
-fn list_inv<T>(this: &T, factory: &Factory) -> ActionFuture<List>
+fn list_inv<T>(this: &T, factory: &Factory) -> impl Future<Output = Result<Vec<Option<ItemStack>>, LocalStr>>
 where
     T: Inventory,
 {
     let server = factory.borrow_server();
     let access = server.load_balance(this.get_accesses()).1;
-    let action = ActionFuture::from(List { addr: access.addr.clone(), side: access.inv_side });
-    server.enqueue_request_group(&access.client, vec![action.clone().into()]);
-    action
+    let action = ActionFuture::from(access.list(false));
+    server.enqueue_request_group(access.get_client(), vec![action.clone().into()]);
+    let weak = factory.weak.clone();
+    async move {
+        let mut stacks = action.await?;
+        alive!(weak, factory);
+        factory.canonicalize_stacks(&mut stacks);
+        Ok(stacks)
+    }
 }
 
-fn extract_output<T>(this: &T, factory: &mut Factory, slot: usize, size: i32) -> ChildTask<Result<(), LocalStr>>
+fn extract_output<T>(this: &T, factory: &mut Factory, slot: usize, size: i64) -> ChildTask<Result<(), LocalStr>>
 where
     T: Inventory,
 {
@@ -70,18 +156,8 @@ where
             alive!(this.get_factory(), factory);
             let server = factory.borrow_server();
             let access = server.load_balance(this.get_accesses()).1;
-            action = ActionFuture::from(Call {
-                addr: access.addr.clone(),
-                func: local_str!("transferItem"),
-                args: vec![
-                    access.inv_side.into(),
-                    access.bus_side.into(),
-                    size.into(),
-                    (slot + 1).into(),
-                    (bus_slot + 1).into(),
-                ],
-            });
-            server.enqueue_request_group(&access.client, vec![action.clone().into()])
+            action = ActionFuture::from(access.to_bus(size, slot, bus_slot));
+            server.enqueue_request_group(access.get_client(), vec![action.clone().into()])
         }
         let result = action.await.map(|_| ());
         alive!(weak, this);
@@ -99,7 +175,7 @@ fn scattering_insert<T, U>(
 ) -> ChildTask<Result<(), LocalStr>>
 where
     T: Inventory,
-    U: IntoIterator<Item = (usize, i32)> + 'static,
+    U: IntoIterator<Item = (usize, i64)> + 'static,
 {
     let bus_slot = factory.bus_allocate();
     let weak = this.get_weak().clone();
@@ -119,18 +195,8 @@ where
                 let server = factory.borrow_server();
                 for (inv_slot, size) in insertions.into_iter() {
                     let access = server.load_balance(this.get_accesses()).1;
-                    let action = ActionFuture::from(Call {
-                        addr: access.addr.clone(),
-                        func: local_str!("transferItem"),
-                        args: vec![
-                            access.bus_side.into(),
-                            access.inv_side.into(),
-                            size.into(),
-                            (bus_slot + 1).into(),
-                            (inv_slot + 1).into(),
-                        ],
-                    });
-                    server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+                    let action = ActionFuture::from(access.from_bus(size, bus_slot, Some(inv_slot)));
+                    server.enqueue_request_group(access.get_client(), vec![action.clone().into()]);
                     tasks.push(spawn(async move { action.await.map(|_| ()) }))
                 }
             }
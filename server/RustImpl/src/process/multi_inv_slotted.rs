@@ -1,9 +1,12 @@
-use super::{extract_output, list_inv, IntoProcess, Inventory, Process};
-use crate::access::{InvAccess, MultiInvAccess};
+use super::{extract_output, list_inv, n_sets_loaded, record_output, IntoProcess, Inventory, Process};
+use crate::access::{InvAccess, ItemAccess, MultiInvAccess};
 use crate::action::{ActionFuture, Call};
 use crate::factory::Factory;
 use crate::item::{Filter, ItemStack};
-use crate::recipe::{compute_demands, Demand, Input, Outputs, Recipe};
+use crate::recipe::{
+    compute_demands, is_batch_ready, link_recipes, propose_demands, Alternative, Demand, DemandPolicy, Input, Outputs,
+    Proposal, Recipe, RecipeLinks,
+};
 use crate::util::{alive, join_outputs, join_tasks, spawn};
 use abort_on_drop::ChildTask;
 use flexstr::{local_str, LocalStr};
@@ -11,31 +14,35 @@ use fnv::{FnvHashMap, FnvHashSet};
 use std::{
     cell::RefCell,
     rc::{Rc, Weak},
+    time::Duration,
 };
 
 impl_input!(MultiInvSlottedInput);
 #[derive(Clone)]
 pub struct MultiInvSlottedInput {
     item: Filter,
-    pub size: i32,
-    pub slots: Vec<(usize, usize, i32)>,
+    pub size: i64,
+    pub slots: Vec<(usize, usize, i64)>,
     allow_backup: bool,
-    extra_backup: i32,
+    extra_backup: i64,
+    alternatives: Vec<Alternative>,
 }
 
 impl MultiInvSlottedInput {
-    pub fn new(item: Filter, slots: Vec<(usize, usize, i32)>) -> Self {
+    pub fn new(item: Filter, slots: Vec<(usize, usize, i64)>) -> Self {
         let size = slots.iter().map(|(_, _, size)| size).sum();
-        Self { item, size, slots, allow_backup: false, extra_backup: 0 }
+        Self { item, size, slots, allow_backup: false, extra_backup: 0, alternatives: Vec::new() }
     }
 }
 
-impl_recipe!(MultiInvSlottedRecipe, MultiInvSlottedInput);
+impl_recipe!(MultiInvSlottedRecipe, MultiInvSlottedInput, batched);
 #[derive(Clone)]
 pub struct MultiInvSlottedRecipe {
     pub outputs: Rc<dyn Outputs>,
     pub inputs: Vec<MultiInvSlottedInput>,
-    pub max_sets: i32,
+    pub max_sets: i64,
+    pub min_sets: i64,
+    pub max_wait: Option<Duration>,
 }
 
 pub type MultiInvExtractFilter = Box<dyn Fn(&Factory, usize, usize, &ItemStack) -> bool>;
@@ -48,10 +55,11 @@ pub struct MultiInvSlottedConfig {
     pub to_extract: Option<MultiInvExtractFilter>,
     pub recipes: Vec<MultiInvSlottedRecipe>,
     pub strict_priority: bool,
+    pub demand_policy: Box<dyn DemandPolicy>,
 }
 
 pub struct EachInvConfig {
-    pub accesses: Vec<InvAccess>,
+    pub accesses: Vec<ItemAccess>,
     pub input_slots: Vec<usize>,
 }
 
@@ -71,7 +79,9 @@ pub struct MultiInvSlottedProcess {
     to_extract: Option<MultiInvExtractFilter>,
     recipes: Vec<MultiInvSlottedRecipe>,
     strict_priority: bool,
+    demand_policy: Box<dyn DemandPolicy>,
     invs: Vec<Rc<RefCell<EachInv>>>,
+    listed: Option<Vec<Vec<Option<ItemStack>>>>,
 }
 
 impl IntoProcess for MultiInvSlottedConfig {
@@ -92,6 +102,7 @@ impl IntoProcess for MultiInvSlottedConfig {
                                     bus_side: each.bus_side,
                                     inv_side: each.inv_side,
                                 }
+                                .into()
                             })),
                             input_slots,
                         },
@@ -107,93 +118,155 @@ impl IntoProcess for MultiInvSlottedConfig {
                 to_extract: self.to_extract,
                 recipes: self.recipes,
                 strict_priority: self.strict_priority,
+                demand_policy: self.demand_policy,
                 invs,
+                listed: None,
             })
         })
     }
 }
 
 impl Process for MultiInvSlottedProcess {
-    fn run(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
-        if self.to_extract.is_none() && compute_demands(factory, &self.recipes).is_empty() {
-            return spawn(async { Ok(()) });
+    fn prepare(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+        if self.to_extract.is_none()
+            && compute_demands(factory, &self.name, &self.recipes, &*self.demand_policy).is_empty()
+        {
+            // Drops what was listed for a cycle that failed before running.
+            let weak = self.weak.clone();
+            return spawn(async move {
+                alive_mut!(weak, this);
+                this.listed = None;
+                Ok(())
+            });
         }
         let stacks = Vec::from_iter(self.invs.iter().map(|inv| spawn(list_inv(&*inv.borrow(), factory))));
         let weak = self.weak.clone();
         spawn(async move {
             let stacks = join_outputs(stacks).await?;
+            alive_mut!(weak, this);
+            this.listed = Some(stacks);
+            Ok(())
+        })
+    }
+
+    fn run(&self, _factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+        if self.listed.is_none() {
+            return spawn(async { Ok(()) });
+        }
+        let weak = self.weak.clone();
+        spawn(async move {
             let mut tasks = Vec::new();
             {
+                let stacks = alive(&weak)?.borrow_mut().listed.take().unwrap();
                 alive!(weak, this);
                 upgrade_mut!(this.factory, factory);
-                let mut existing_inputs = FnvHashMap::<(usize, usize), Option<ItemStack>>::default();
-                for (i, inv) in this.invs.iter().enumerate() {
-                    for slot in &inv.borrow().config.input_slots {
-                        existing_inputs.insert((i, *slot), None);
-                    }
-                }
+                let existing_inputs = this.existing_inputs(&stacks);
                 for (i, stacks) in stacks.into_iter().enumerate() {
                     for (slot, stack) in stacks.into_iter().enumerate() {
                         if let Some(stack) = stack {
-                            if let Some(existing_input) = existing_inputs.get_mut(&(i, slot)) {
-                                *existing_input = Some(stack)
+                            if existing_inputs.contains_key(&(i, slot)) {
+                                continue;
                             } else if let Some(ref to_extract) = this.to_extract {
                                 if to_extract(factory, i, slot, &stack) {
-                                    tasks.push(extract_output(
-                                        &*this.invs[i].borrow(),
-                                        factory,
-                                        slot,
-                                        stack.item.max_size,
-                                    ))
+                                    let extraction =
+                                        extract_output(&*this.invs[i].borrow(), factory, slot, stack.item.max_size);
+                                    tasks.push(record_output(factory, &this.name, &this.recipes, &stack, extraction))
                                 }
                             }
                         }
                     }
                 }
-                let mut demands = compute_demands(factory, &this.recipes);
-                if this.strict_priority {
-                    demands.truncate(1)
+                for (i_recipe, recipe) in this.recipes.iter().enumerate() {
+                    let slots = recipe
+                        .inputs
+                        .iter()
+                        .flat_map(|x| x.slots.iter().map(move |&(inv, slot, mult)| (x, (inv, slot), mult)));
+                    recipe.outputs.add_in_flight(
+                        factory,
+                        n_sets_loaded(&existing_inputs, slots),
+                        None,
+                        (&this.name, i_recipe),
+                    )
                 }
-                'recipe: for mut demand in demands.into_iter() {
-                    let recipe = &this.recipes[demand.i_recipe];
-                    let mut used_slots = FnvHashSet::<(usize, usize)>::default();
-                    for (i_input, input) in recipe.inputs.iter().enumerate() {
-                        for (inv, inv_slot, mult) in &input.slots {
-                            let slot = (*inv, *inv_slot);
-                            let existing_input = existing_inputs.get(&slot).unwrap();
-                            let existing_size = if let Some(existing_input) = existing_input {
-                                if existing_input.item != demand.inputs.items[i_input] {
-                                    continue 'recipe;
-                                }
-                                existing_input.size
-                            } else {
-                                0
-                            };
-                            demand.inputs.n_sets = demand.inputs.n_sets.min(
-                                ((recipe.max_sets * mult).min(demand.inputs.items[i_input].max_size) - existing_size)
-                                    / mult,
-                            );
-                            if demand.inputs.n_sets <= 0 {
-                                continue 'recipe;
-                            }
-                            used_slots.insert(slot);
-                        }
-                    }
-                    for (slot, existing_input) in &existing_inputs {
-                        if existing_input.is_some() && !used_slots.contains(slot) {
-                            continue 'recipe;
-                        }
-                    }
-                    tasks.push(this.execute_recipe(factory, demand));
-                    break;
+                if let Some(demand) = this.plan(factory, &existing_inputs) {
+                    this.demand_policy.on_executed(demand.i_recipe);
+                    let outputs = &this.recipes[demand.i_recipe].outputs;
+                    outputs.add_in_flight(factory, demand.inputs.n_sets, None, (&this.name, demand.i_recipe));
+                    factory.record_sets((&this.name, demand.i_recipe), demand.inputs.n_sets);
+                    tasks.push(this.execute_recipe(factory, demand))
                 }
             }
             join_tasks(tasks).await
         })
     }
+
+    fn propose(&self, factory: &Factory) -> Vec<Proposal> {
+        let Some(ref stacks) = self.listed else { return Vec::new() };
+        propose_demands(&self.recipes, Vec::from_iter(self.plan(factory, &self.existing_inputs(stacks))))
+    }
+
+    fn link_recipes(&self) -> Vec<RecipeLinks> { link_recipes(&self.recipes) }
 }
 
 impl MultiInvSlottedProcess {
+    fn existing_inputs(&self, stacks: &[Vec<Option<ItemStack>>]) -> FnvHashMap<(usize, usize), Option<ItemStack>> {
+        let mut result = FnvHashMap::default();
+        for (i, inv) in self.invs.iter().enumerate() {
+            for &slot in &inv.borrow().config.input_slots {
+                result.insert((i, slot), stacks[i].get(slot).cloned().flatten());
+            }
+        }
+        result
+    }
+
+    // The demand to load given what's in the input slots, clamped to what fits.
+    fn plan(
+        &self,
+        factory: &Factory,
+        existing_inputs: &FnvHashMap<(usize, usize), Option<ItemStack>>,
+    ) -> Option<Demand> {
+        let mut demands = compute_demands(factory, &self.name, &self.recipes, &*self.demand_policy);
+        if self.strict_priority {
+            demands.truncate(1)
+        }
+        'recipe: for mut demand in demands.into_iter() {
+            let recipe = &self.recipes[demand.i_recipe];
+            let mut used_slots = FnvHashSet::<(usize, usize)>::default();
+            for (i_input, input) in recipe.inputs.iter().enumerate() {
+                for (inv, inv_slot, mult) in &input.slots {
+                    let slot = (*inv, *inv_slot);
+                    let existing_input = existing_inputs.get(&slot).unwrap();
+                    let existing_size = if let Some(existing_input) = existing_input {
+                        if existing_input.item != demand.inputs.items[i_input] {
+                            continue 'recipe;
+                        }
+                        existing_input.size
+                    } else {
+                        0
+                    };
+                    demand.inputs.n_sets = demand.inputs.n_sets.min(
+                        (recipe.max_sets.saturating_mul(*mult).min(demand.inputs.items[i_input].max_size)
+                            - existing_size)
+                            / mult,
+                    );
+                    if demand.inputs.n_sets <= 0 {
+                        continue 'recipe;
+                    }
+                    used_slots.insert(slot);
+                }
+            }
+            for (slot, existing_input) in existing_inputs {
+                if existing_input.is_some() && !used_slots.contains(slot) {
+                    continue 'recipe;
+                }
+            }
+            if is_batch_ready(factory, recipe, demand.inputs.n_sets) {
+                return Some(demand);
+            }
+        }
+        None
+    }
+
     fn execute_recipe(&self, factory: &mut Factory, demand: Demand) -> ChildTask<Result<(), LocalStr>> {
         let mut bus_slots = Vec::new();
         let slots_to_free = Rc::new(RefCell::new(Vec::new()));
@@ -16,7 +16,7 @@ use tokio::time::Instant;
 
 trait ReactorProcess {
     fn get_accesses(&self) -> &Vec<ComponentAccess>;
-    fn n_cyanite_wanted(&self) -> i32;
+    fn n_cyanite_wanted(&self) -> i64;
     fn has_turbine(&self) -> bool;
 }
 
@@ -24,7 +24,7 @@ macro_rules! impl_reactor_process {
     ($i:ident) => {
         impl ReactorProcess for $i {
             fn get_accesses(&self) -> &Vec<ComponentAccess> { &self.config.accesses }
-            fn n_cyanite_wanted(&self) -> i32 { self.config.n_cyanite_wanted }
+            fn n_cyanite_wanted(&self) -> i64 { self.config.n_cyanite_wanted }
             fn has_turbine(&self) -> bool { self.config.has_turbine }
         }
     };
@@ -72,7 +72,7 @@ where
 pub struct HysteresisReactorConfig {
     pub name: LocalStr,
     pub accesses: Vec<ComponentAccess>,
-    pub n_cyanite_wanted: i32,
+    pub n_cyanite_wanted: i64,
     pub has_turbine: bool,
     pub lower_bound: f64, // typical: 0.3
     pub upper_bound: f64, // typical: 0.7
@@ -144,7 +144,7 @@ impl Process for HysteresisReactorProcess {
 pub struct ProportionalReactorConfig {
     pub name: LocalStr,
     pub accesses: Vec<ComponentAccess>,
-    pub n_cyanite_wanted: i32,
+    pub n_cyanite_wanted: i64,
     pub has_turbine: bool,
 }
 
@@ -205,7 +205,7 @@ impl Process for ProportionalReactorProcess {
 pub struct PIDReactorConfig {
     pub name: LocalStr,
     pub accesses: Vec<ComponentAccess>,
-    pub n_cyanite_wanted: i32,
+    pub n_cyanite_wanted: i64,
     pub has_turbine: bool,
     pub k_p: f64, // typical: 1.00
     pub k_i: f64, // typical: 0.01
@@ -1,9 +1,15 @@
-use super::super::access::InvAccess;
+use super::super::access::ItemAccess;
 use super::super::factory::Factory;
 use super::super::item::{Filter, ItemStack};
-use super::super::recipe::{compute_demands, resolve_inputs, Demand, Input, Outputs, Recipe};
+use super::super::recipe::{
+    compute_demands, link_recipes, propose_demands, resolve_inputs, Alternative, Demand, DemandPolicy, Input, Outputs,
+    Proposal, Recipe, RecipeLinks, ResolvedInputs,
+};
 use super::super::util::{alive, join_tasks, spawn};
-use super::{extract_output, list_inv, scattering_insert, ExtractFilter, IntoProcess, Inventory, Process};
+use super::{
+    extract_output, list_inv, n_sets_buffered, record_output, scattering_insert, ExtractFilter, IntoProcess, Inventory,
+    Process,
+};
 use abort_on_drop::ChildTask;
 use flexstr::{local_fmt, LocalStr};
 use fnv::FnvHashMap;
@@ -16,13 +22,16 @@ impl_input!(ScatteringInput);
 #[derive(Clone)]
 pub struct ScatteringInput {
     item: Filter,
-    size: i32,
+    size: i64,
     allow_backup: bool,
-    extra_backup: i32,
+    extra_backup: i64,
+    alternatives: Vec<Alternative>,
 }
 
 impl ScatteringInput {
-    pub fn new(item: Filter) -> Self { ScatteringInput { item, size: 1, allow_backup: false, extra_backup: 0 } }
+    pub fn new(item: Filter) -> Self {
+        ScatteringInput { item, size: 1, allow_backup: false, extra_backup: 0, alternatives: Vec::new() }
+    }
 }
 
 impl_recipe!(ScatteringRecipe, ScatteringInput);
@@ -40,12 +49,13 @@ impl ScatteringRecipe {
 
 pub struct ScatteringConfig {
     pub name: LocalStr,
-    pub accesses: Vec<InvAccess>,
+    pub accesses: Vec<ItemAccess>,
     // plant_sower: 6, 7, .., 14
     pub input_slots: Vec<usize>,
     pub to_extract: Option<ExtractFilter>,
     pub recipes: Vec<ScatteringRecipe>,
-    pub max_per_slot: i32,
+    pub demand_policy: Box<dyn DemandPolicy>,
+    pub max_per_slot: i64,
 }
 
 impl_inventory!(ScatteringProcess);
@@ -53,82 +63,93 @@ pub struct ScatteringProcess {
     weak: Weak<RefCell<ScatteringProcess>>,
     config: ScatteringConfig,
     factory: Weak<RefCell<Factory>>,
+    listed: Option<Vec<Option<ItemStack>>>,
 }
 
 impl IntoProcess for ScatteringConfig {
     type Output = ScatteringProcess;
     fn into_process(self, factory: &Factory) -> Rc<RefCell<Self::Output>> {
         Rc::new_cyclic(|weak| {
-            RefCell::new(Self::Output { weak: weak.clone(), config: self, factory: factory.weak.clone() })
+            RefCell::new(Self::Output { weak: weak.clone(), config: self, factory: factory.weak.clone(), listed: None })
         })
     }
 }
 
 impl Process for ScatteringProcess {
-    fn run(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
-        if self.config.to_extract.is_none() && compute_demands(factory, &self.config.recipes).is_empty() {
-            return spawn(async { Ok(()) });
+    fn prepare(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+        if self.config.to_extract.is_none()
+            && compute_demands(factory, &self.config.name, &self.config.recipes, &*self.config.demand_policy).is_empty()
+        {
+            // Drops what was listed for a cycle that failed before running.
+            let weak = self.weak.clone();
+            return spawn(async move {
+                alive_mut!(weak, this);
+                this.listed = None;
+                Ok(())
+            });
         }
         let stacks = list_inv(self, factory);
         let weak = self.weak.clone();
         spawn(async move {
-            let mut stacks = stacks.await?;
+            let stacks = stacks.await?;
+            alive_mut!(weak, this);
+            for slot in &this.config.input_slots {
+                if *slot >= stacks.len() {
+                    return Err(local_fmt!("{}: invalid slot", this.config.name));
+                }
+            }
+            this.listed = Some(stacks);
+            Ok(())
+        })
+    }
+
+    fn run(&self, _factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+        if self.listed.is_none() {
+            return spawn(async { Ok(()) });
+        }
+        let weak = self.weak.clone();
+        spawn(async move {
             let mut tasks = Vec::new();
             {
+                let mut stacks = alive(&weak)?.borrow_mut().listed.take().unwrap();
                 alive!(weak, this);
                 upgrade_mut!(this.factory, factory);
-                let mut is_input_slot = vec![false; stacks.len()];
-                for slot in &this.config.input_slots {
-                    if *slot >= stacks.len() {
-                        return Err(local_fmt!("{}: invalid slot", this.config.name));
-                    }
-                    is_input_slot[*slot] = true
-                }
                 if let Some(ref to_extract) = this.config.to_extract {
                     for (slot, stack) in stacks.iter().enumerate() {
                         if let Some(stack) = stack {
-                            if !is_input_slot[slot] && to_extract(factory, slot, stack) {
-                                tasks.push(extract_output(this, factory, slot, stack.item.max_size))
+                            if !this.config.input_slots.contains(&slot) && to_extract(factory, slot, stack) {
+                                let extraction = extract_output(this, factory, slot, stack.item.max_size);
+                                tasks.push(record_output(
+                                    factory,
+                                    &this.config.name,
+                                    &this.config.recipes,
+                                    stack,
+                                    extraction,
+                                ))
                             }
                         }
                     }
                 }
-                for Demand { i_recipe, .. } in compute_demands(factory, &this.config.recipes) {
-                    if let Some(mut inputs) = resolve_inputs(factory, &this.config.recipes[i_recipe]) {
-                        let mut insertions = FnvHashMap::<usize, i32>::default();
-                        let mut n_inserted = 0;
-                        while inputs.n_sets > 0 {
-                            let mut best = None;
-                            for slot in &this.config.input_slots {
-                                if let Some(ref stack) = stacks[*slot] {
-                                    if stack.item == inputs.items[0] {
-                                        if let Some((_, best_size)) = best {
-                                            if stack.size >= best_size {
-                                                continue;
-                                            }
-                                        }
-                                        best = Some((*slot, stack.size))
-                                    }
-                                } else {
-                                    best = Some((*slot, 0));
-                                    break;
-                                }
-                            }
-                            let Some((slot, size)) = best else { break };
-                            if size >= this.config.max_per_slot.min(inputs.items[0].max_size) {
-                                break;
-                            }
-                            inputs.n_sets -= 1;
-                            n_inserted += 1;
-                            *insertions.entry(slot).or_default() += 1;
-                            let stack = &mut stacks[slot];
-                            if let Some(ref mut stack) = stack {
-                                stack.size += 1
-                            } else {
-                                *stack = Some(ItemStack { item: inputs.items[0].clone(), size: 1 })
-                            }
-                        }
+                let loaded = this.config.input_slots.iter().filter_map(|slot| stacks[*slot].as_ref());
+                let n_sets_loaded = n_sets_buffered(&this.config.recipes, loaded);
+                for (i_recipe, (recipe, n_sets)) in this.config.recipes.iter().zip(n_sets_loaded).enumerate() {
+                    recipe.outputs.add_in_flight(factory, n_sets, None, (&this.config.name, i_recipe))
+                }
+                for Demand { i_recipe, .. } in
+                    compute_demands(factory, &this.config.name, &this.config.recipes, &*this.config.demand_policy)
+                {
+                    // Resolved again as earlier recipes have reserved their inputs.
+                    if let Some(inputs) = resolve_inputs(factory, &this.config.recipes[i_recipe]) {
+                        let (n_inserted, insertions) = this.plan_insertions(&mut stacks, &inputs);
                         if n_inserted > 0 {
+                            this.config.demand_policy.on_executed(i_recipe);
+                            this.config.recipes[i_recipe].outputs.add_in_flight(
+                                factory,
+                                n_inserted,
+                                None,
+                                (&this.config.name, i_recipe),
+                            );
+                            factory.record_sets((&this.config.name, i_recipe), n_inserted);
                             let reservation = factory.reserve_item(&this.config.name, &inputs.items[0], n_inserted);
                             tasks.push(scattering_insert(this, factory, reservation, insertions))
                         }
@@ -138,4 +159,61 @@ impl Process for ScatteringProcess {
             join_tasks(tasks).await
         })
     }
+
+    fn propose(&self, factory: &Factory) -> Vec<Proposal> {
+        let Some(ref stacks) = self.listed else { return Vec::new() };
+        let mut stacks = stacks.clone();
+        let mut demands =
+            compute_demands(factory, &self.config.name, &self.config.recipes, &*self.config.demand_policy);
+        for demand in &mut demands {
+            demand.inputs.n_sets = self.plan_insertions(&mut stacks, &demand.inputs).0
+        }
+        demands.retain(|x| x.inputs.n_sets > 0);
+        propose_demands(&self.config.recipes, demands)
+    }
+
+    fn link_recipes(&self) -> Vec<RecipeLinks> { link_recipes(&self.config.recipes) }
+}
+
+impl ScatteringProcess {
+    // Spreads sets over the input slots as evenly as fits, returning the number inserted and into which slots.
+    fn plan_insertions(
+        &self,
+        stacks: &mut [Option<ItemStack>],
+        inputs: &ResolvedInputs,
+    ) -> (i64, FnvHashMap<usize, i64>) {
+        let mut insertions = FnvHashMap::<usize, i64>::default();
+        let mut n_inserted = 0;
+        while n_inserted < inputs.n_sets {
+            let mut best = None;
+            for slot in &self.config.input_slots {
+                if let Some(ref stack) = stacks[*slot] {
+                    if stack.item == inputs.items[0] {
+                        if let Some((_, best_size)) = best {
+                            if stack.size >= best_size {
+                                continue;
+                            }
+                        }
+                        best = Some((*slot, stack.size))
+                    }
+                } else {
+                    best = Some((*slot, 0));
+                    break;
+                }
+            }
+            let Some((slot, size)) = best else { break };
+            if size >= self.config.max_per_slot.min(inputs.items[0].max_size) {
+                break;
+            }
+            n_inserted += 1;
+            *insertions.entry(slot).or_default() += 1;
+            let stack = &mut stacks[slot];
+            if let Some(ref mut stack) = stack {
+                stack.size += 1
+            } else {
+                *stack = Some(ItemStack { item: inputs.items[0].clone(), size: 1 })
+            }
+        }
+        (n_inserted, insertions)
+    }
 }
@@ -1,56 +1,65 @@
-use super::super::access::InvAccess;
-use super::super::action::{ActionFuture, Call};
+use super::super::access::{Access, ItemAccess};
+use super::super::action::ActionFuture;
 use super::super::factory::Factory;
 use super::super::item::{Filter, ItemStack};
-use super::super::recipe::{compute_demands, Demand, Input, Outputs, Recipe};
+use super::super::recipe::{
+    compute_demands, is_batch_ready, link_recipes, propose_demands, Alternative, Demand, DemandPolicy, Input, Outputs,
+    Proposal, Recipe, RecipeLinks,
+};
 use super::super::util::{alive, join_outputs, join_tasks, spawn};
-use super::{extract_output, list_inv, ExtractFilter, IntoProcess, Inventory, Process};
+use super::{extract_output, list_inv, n_sets_loaded, record_output, ExtractFilter, IntoProcess, Inventory, Process};
 use abort_on_drop::ChildTask;
-use flexstr::{local_str, LocalStr};
+use flexstr::LocalStr;
 use fnv::{FnvHashMap, FnvHashSet};
 use std::{
     cell::RefCell,
     rc::{Rc, Weak},
+    time::Duration,
 };
 
 #[derive(Clone)]
 pub struct SlottedInput {
     item: Filter,
-    size: i32,
-    slots: Vec<(usize, i32)>,
+    size: i64,
+    slots: Vec<(usize, i64)>,
     allow_backup: bool,
-    extra_backup: i32,
+    extra_backup: i64,
+    alternatives: Vec<Alternative>,
 }
 
 impl_input!(SlottedInput);
 impl SlottedInput {
-    pub fn new(item: Filter, slots: Vec<(usize, i32)>) -> Self {
+    pub fn new(item: Filter, slots: Vec<(usize, i64)>) -> Self {
         let size = slots.iter().map(|(_, size)| size).sum();
-        SlottedInput { item, size, slots, allow_backup: false, extra_backup: 0 }
+        SlottedInput { item, size, slots, allow_backup: false, extra_backup: 0, alternatives: Vec::new() }
     }
 }
 
-impl_recipe!(SlottedRecipe, SlottedInput);
+impl_recipe!(SlottedRecipe, SlottedInput, batched);
 #[derive(Clone)]
 pub struct SlottedRecipe {
     pub outputs: Rc<dyn Outputs>,
     pub inputs: Vec<SlottedInput>,
-    pub max_sets: i32,
+    pub max_sets: i64,
+    pub min_sets: i64,
+    pub max_wait: Option<Duration>,
 }
 
 pub struct SlottedConfig {
     pub name: LocalStr,
-    pub accesses: Vec<InvAccess>,
+    pub accesses: Vec<ItemAccess>,
     pub input_slots: Vec<usize>,
     pub to_extract: Option<ExtractFilter>,
     pub recipes: Vec<SlottedRecipe>,
     pub strict_priority: bool,
+    pub demand_policy: Box<dyn DemandPolicy>,
 }
 
 pub struct SlottedProcess {
     weak: Weak<RefCell<SlottedProcess>>,
     config: SlottedConfig,
     factory: Weak<RefCell<Factory>>,
+    listed: Option<Vec<Option<ItemStack>>>,
 }
 
 impl_inventory!(SlottedProcess);
@@ -59,82 +68,147 @@ impl IntoProcess for SlottedConfig {
     type Output = SlottedProcess;
     fn into_process(self, factory: &Factory) -> Rc<RefCell<Self::Output>> {
         Rc::new_cyclic(|weak| {
-            RefCell::new(Self::Output { weak: weak.clone(), config: self, factory: factory.weak.clone() })
+            RefCell::new(Self::Output { weak: weak.clone(), config: self, factory: factory.weak.clone(), listed: None })
         })
     }
 }
 
 impl Process for SlottedProcess {
-    fn run(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
-        if self.config.to_extract.is_none() && compute_demands(factory, &self.config.recipes).is_empty() {
-            return spawn(async { Ok(()) });
+    fn prepare(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+        if self.config.to_extract.is_none()
+            && compute_demands(factory, &self.config.name, &self.config.recipes, &*self.config.demand_policy).is_empty()
+        {
+            // Drops what was listed for a cycle that failed before running.
+            let weak = self.weak.clone();
+            return spawn(async move {
+                alive_mut!(weak, this);
+                this.listed = None;
+                Ok(())
+            });
         }
         let stacks = list_inv(self, factory);
         let weak = self.weak.clone();
         spawn(async move {
             let stacks = stacks.await?;
+            alive_mut!(weak, this);
+            this.listed = Some(stacks);
+            Ok(())
+        })
+    }
+
+    fn run(&self, _factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+        if self.listed.is_none() {
+            return spawn(async { Ok(()) });
+        }
+        let weak = self.weak.clone();
+        spawn(async move {
             let mut tasks = Vec::new();
             {
+                let stacks = alive(&weak)?.borrow_mut().listed.take().unwrap();
                 alive!(weak, this);
                 upgrade_mut!(this.factory, factory);
-                let mut existing_inputs = FnvHashMap::<usize, Option<ItemStack>>::default();
-                for slot in &this.config.input_slots {
-                    existing_inputs.insert(*slot, None);
-                }
+                let existing_inputs = this.existing_inputs(&stacks);
                 for (slot, stack) in stacks.into_iter().enumerate() {
                     if let Some(stack) = stack {
-                        if let Some(existing_input) = existing_inputs.get_mut(&slot) {
-                            *existing_input = Some(stack)
+                        if existing_inputs.contains_key(&slot) {
+                            continue;
                         } else if let Some(ref to_extract) = this.config.to_extract {
                             if to_extract(factory, slot, &stack) {
-                                tasks.push(extract_output(this, factory, slot, stack.item.max_size))
+                                let extraction = extract_output(this, factory, slot, stack.item.max_size);
+                                tasks.push(record_output(
+                                    factory,
+                                    &this.config.name,
+                                    &this.config.recipes,
+                                    &stack,
+                                    extraction,
+                                ))
                             }
                         }
                     }
                 }
-                let mut demands = compute_demands(factory, &this.config.recipes);
-                if this.config.strict_priority {
-                    demands.truncate(1)
+                for (i_recipe, recipe) in this.config.recipes.iter().enumerate() {
+                    let slots =
+                        recipe.inputs.iter().flat_map(|x| x.slots.iter().map(move |&(slot, mult)| (x, slot, mult)));
+                    recipe.outputs.add_in_flight(
+                        factory,
+                        n_sets_loaded(&existing_inputs, slots),
+                        None,
+                        (&this.config.name, i_recipe),
+                    )
                 }
-                'recipe: for mut demand in demands.into_iter() {
-                    let recipe = &this.config.recipes[demand.i_recipe];
-                    let mut used_slots = FnvHashSet::<usize>::default();
-                    for (i_input, input) in recipe.inputs.iter().enumerate() {
-                        for (slot, mult) in &input.slots {
-                            let existing_input = existing_inputs.get(slot).unwrap();
-                            let existing_size = if let Some(existing_input) = existing_input {
-                                if existing_input.item != demand.inputs.items[i_input] {
-                                    continue 'recipe;
-                                }
-                                existing_input.size
-                            } else {
-                                0
-                            };
-                            demand.inputs.n_sets = demand.inputs.n_sets.min(
-                                ((recipe.max_sets * mult).min(demand.inputs.items[i_input].max_size) - existing_size)
-                                    / mult,
-                            );
-                            if demand.inputs.n_sets <= 0 {
-                                continue 'recipe;
-                            }
-                            used_slots.insert(*slot);
-                        }
-                    }
-                    for (slot, existing_input) in &existing_inputs {
-                        if existing_input.is_some() && !used_slots.contains(slot) {
-                            continue 'recipe;
-                        }
-                    }
-                    tasks.push(this.execute_recipe(factory, demand));
-                    break;
+                if let Some(demand) = this.plan(factory, &existing_inputs) {
+                    this.config.demand_policy.on_executed(demand.i_recipe);
+                    this.config.recipes[demand.i_recipe].outputs.add_in_flight(
+                        factory,
+                        demand.inputs.n_sets,
+                        None,
+                        (&this.config.name, demand.i_recipe),
+                    );
+                    factory.record_sets((&this.config.name, demand.i_recipe), demand.inputs.n_sets);
+                    tasks.push(this.execute_recipe(factory, demand))
                 }
             }
             join_tasks(tasks).await
         })
     }
+
+    fn propose(&self, factory: &Factory) -> Vec<Proposal> {
+        let Some(ref stacks) = self.listed else { return Vec::new() };
+        propose_demands(&self.config.recipes, Vec::from_iter(self.plan(factory, &self.existing_inputs(stacks))))
+    }
+
+    fn link_recipes(&self) -> Vec<RecipeLinks> { link_recipes(&self.config.recipes) }
 }
 
 impl SlottedProcess {
+    fn existing_inputs(&self, stacks: &[Option<ItemStack>]) -> FnvHashMap<usize, Option<ItemStack>> {
+        FnvHashMap::from_iter(self.config.input_slots.iter().map(|&slot| (slot, stacks.get(slot).cloned().flatten())))
+    }
+
+    // The demand to load given what's in the input slots, clamped to what fits.
+    fn plan(&self, factory: &Factory, existing_inputs: &FnvHashMap<usize, Option<ItemStack>>) -> Option<Demand> {
+        let mut demands =
+            compute_demands(factory, &self.config.name, &self.config.recipes, &*self.config.demand_policy);
+        if self.config.strict_priority {
+            demands.truncate(1)
+        }
+        'recipe: for mut demand in demands.into_iter() {
+            let recipe = &self.config.recipes[demand.i_recipe];
+            let mut used_slots = FnvHashSet::<usize>::default();
+            for (i_input, input) in recipe.inputs.iter().enumerate() {
+                for (slot, mult) in &input.slots {
+                    let existing_input = existing_inputs.get(slot).unwrap();
+                    let existing_size = if let Some(existing_input) = existing_input {
+                        if existing_input.item != demand.inputs.items[i_input] {
+                            continue 'recipe;
+                        }
+                        existing_input.size
+                    } else {
+                        0
+                    };
+                    demand.inputs.n_sets = demand.inputs.n_sets.min(
+                        (recipe.max_sets.saturating_mul(*mult).min(demand.inputs.items[i_input].max_size)
+                            - existing_size)
+                            / mult,
+                    );
+                    if demand.inputs.n_sets <= 0 {
+                        continue 'recipe;
+                    }
+                    used_slots.insert(*slot);
+                }
+            }
+            for (slot, existing_input) in existing_inputs {
+                if existing_input.is_some() && !used_slots.contains(slot) {
+                    continue 'recipe;
+                }
+            }
+            if is_batch_ready(factory, recipe, demand.inputs.n_sets) {
+                return Some(demand);
+            }
+        }
+        None
+    }
+
     fn execute_recipe(&self, factory: &mut Factory, demand: Demand) -> ChildTask<Result<(), LocalStr>> {
         let mut bus_slots = Vec::new();
         let slots_to_free = Rc::new(RefCell::new(Vec::new()));
@@ -171,22 +245,16 @@ impl SlottedProcess {
                     let recipe = &this.config.recipes[demand.i_recipe];
                     for (i_input, input) in recipe.inputs.iter().enumerate() {
                         for (inv_slot, mult) in &input.slots {
-                            let action = ActionFuture::from(Call {
-                                addr: access.addr.clone(),
-                                func: local_str!("transferItem"),
-                                args: vec![
-                                    access.bus_side.into(),
-                                    access.inv_side.into(),
-                                    (demand.inputs.n_sets * mult).into(),
-                                    (bus_slots[i_input] + 1).into(),
-                                    (inv_slot + 1).into(),
-                                ],
-                            });
+                            let action = ActionFuture::from(access.from_bus(
+                                demand.inputs.n_sets * mult,
+                                bus_slots[i_input],
+                                Some(*inv_slot),
+                            ));
                             group.push(action.clone().into());
                             tasks.push(spawn(async move { action.await.map(|_| ()) }));
                         }
                     }
-                    server.enqueue_request_group(&access.client, group)
+                    server.enqueue_request_group(access.get_client(), group)
                 }
                 join_tasks(tasks).await?;
                 alive!(weak, this);
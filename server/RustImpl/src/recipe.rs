@@ -1,15 +1,34 @@
 use super::item::{Filter, Item};
 use crate::factory::Factory;
+use crate::overrides::OverrideKind;
 use flexstr::LocalStr;
 use fnv::FnvHashMap;
 use std::{
+    cell::{Cell, RefCell},
     cmp::{max_by, min_by},
     collections::hash_map::Entry,
+    iter::once,
     rc::Rc,
+    time::Duration,
 };
+use tokio::time::Instant;
+
+// A recipe by the name of its process and its index there, for looking up learned yields.
+pub type RecipeId<'a> = (&'a str, usize);
 
 pub trait Outputs {
     fn get_priority(&self, factory: &Factory) -> Option<f64>;
+    // Reports sets that are loaded into a machine but whose outputs haven't reached storage yet.
+    // `per_set` is the declared yield; yields learned for each output of `recipe` take precedence.
+    fn add_in_flight(&self, _factory: &mut Factory, _n_sets: i64, _per_set: Option<f64>, _recipe: RecipeId) {}
+    // Outputs expected per set, for weighing how many sets the inputs allow.
+    fn get_per_set(&self, _factory: &Factory, per_set: Option<f64>, _recipe: RecipeId) -> f64 { per_set.unwrap_or(1.) }
+    // Items produced, for analyzing the recipe graph.
+    fn for_each_filter(&self, _f: &mut dyn FnMut(&Filter)) {}
+    // Items produced with their declared amount per set.
+    fn for_each_yield(&self, per_set: Option<f64>, f: &mut dyn FnMut(&Filter, f64)) {
+        self.for_each_filter(&mut |x| f(x, per_set.unwrap_or(1.)))
+    }
 }
 
 impl<T: Fn(&Factory) -> Option<f64>> Outputs for T {
@@ -21,21 +40,104 @@ pub trait BoxedOutputs {
     fn or(self, other: Self) -> Self;
     fn not(self) -> Self;
     fn map_priority(self, f: impl Fn(&Factory, f64) -> f64 + 'static) -> Self;
+    // Each set is expected to yield `n` of the outputs on average, for in-flight accounting.
+    fn per_set(self, n: f64) -> Self;
 }
 
-impl BoxedOutputs for Rc<dyn Outputs> {
-    fn and(self, other: Self) -> Self {
-        Rc::new(move |factory: &_| {
-            max_by(self.get_priority(factory), other.get_priority(factory), |x, y| x.partial_cmp(y).unwrap())
-        })
+struct AndOutputs(Rc<dyn Outputs>, Rc<dyn Outputs>);
+impl Outputs for AndOutputs {
+    fn get_priority(&self, factory: &Factory) -> Option<f64> {
+        max_by(self.0.get_priority(factory), self.1.get_priority(factory), |x, y| x.partial_cmp(y).unwrap())
     }
 
-    fn or(self, other: Self) -> Self {
-        Rc::new(move |factory: &_| {
-            min_by(self.get_priority(factory), other.get_priority(factory), |x, y| x.partial_cmp(y).unwrap())
-        })
+    fn add_in_flight(&self, factory: &mut Factory, n_sets: i64, per_set: Option<f64>, recipe: RecipeId) {
+        self.0.add_in_flight(factory, n_sets, per_set, recipe);
+        self.1.add_in_flight(factory, n_sets, per_set, recipe)
+    }
+
+    fn get_per_set(&self, factory: &Factory, per_set: Option<f64>, recipe: RecipeId) -> f64 {
+        self.0.get_per_set(factory, per_set, recipe).max(self.1.get_per_set(factory, per_set, recipe))
+    }
+
+    fn for_each_filter(&self, f: &mut dyn FnMut(&Filter)) {
+        self.0.for_each_filter(f);
+        self.1.for_each_filter(f)
+    }
+
+    fn for_each_yield(&self, per_set: Option<f64>, f: &mut dyn FnMut(&Filter, f64)) {
+        self.0.for_each_yield(per_set, f);
+        self.1.for_each_yield(per_set, f)
+    }
+}
+
+struct OrOutputs(Rc<dyn Outputs>, Rc<dyn Outputs>);
+impl Outputs for OrOutputs {
+    fn get_priority(&self, factory: &Factory) -> Option<f64> {
+        min_by(self.0.get_priority(factory), self.1.get_priority(factory), |x, y| x.partial_cmp(y).unwrap())
+    }
+
+    fn add_in_flight(&self, factory: &mut Factory, n_sets: i64, per_set: Option<f64>, recipe: RecipeId) {
+        self.0.add_in_flight(factory, n_sets, per_set, recipe);
+        self.1.add_in_flight(factory, n_sets, per_set, recipe)
+    }
+
+    fn get_per_set(&self, factory: &Factory, per_set: Option<f64>, recipe: RecipeId) -> f64 {
+        self.0.get_per_set(factory, per_set, recipe).max(self.1.get_per_set(factory, per_set, recipe))
+    }
+
+    fn for_each_filter(&self, f: &mut dyn FnMut(&Filter)) {
+        self.0.for_each_filter(f);
+        self.1.for_each_filter(f)
+    }
+
+    fn for_each_yield(&self, per_set: Option<f64>, f: &mut dyn FnMut(&Filter, f64)) {
+        self.0.for_each_yield(per_set, f);
+        self.1.for_each_yield(per_set, f)
+    }
+}
+
+struct MapPriority<F>(Rc<dyn Outputs>, F);
+impl<F: Fn(&Factory, f64) -> f64> Outputs for MapPriority<F> {
+    fn get_priority(&self, factory: &Factory) -> Option<f64> {
+        self.0.get_priority(factory).map(|x| self.1(factory, x))
+    }
+    fn add_in_flight(&self, factory: &mut Factory, n_sets: i64, per_set: Option<f64>, recipe: RecipeId) {
+        self.0.add_in_flight(factory, n_sets, per_set, recipe)
     }
 
+    fn get_per_set(&self, factory: &Factory, per_set: Option<f64>, recipe: RecipeId) -> f64 {
+        self.0.get_per_set(factory, per_set, recipe)
+    }
+
+    fn for_each_filter(&self, f: &mut dyn FnMut(&Filter)) { self.0.for_each_filter(f) }
+
+    fn for_each_yield(&self, per_set: Option<f64>, f: &mut dyn FnMut(&Filter, f64)) {
+        self.0.for_each_yield(per_set, f)
+    }
+}
+
+struct PerSet(Rc<dyn Outputs>, f64);
+impl Outputs for PerSet {
+    fn get_priority(&self, factory: &Factory) -> Option<f64> { self.0.get_priority(factory) }
+    fn add_in_flight(&self, factory: &mut Factory, n_sets: i64, per_set: Option<f64>, recipe: RecipeId) {
+        self.0.add_in_flight(factory, n_sets, Some(per_set.unwrap_or(self.1)), recipe)
+    }
+
+    fn get_per_set(&self, factory: &Factory, per_set: Option<f64>, recipe: RecipeId) -> f64 {
+        self.0.get_per_set(factory, Some(per_set.unwrap_or(self.1)), recipe)
+    }
+
+    fn for_each_filter(&self, f: &mut dyn FnMut(&Filter)) { self.0.for_each_filter(f) }
+
+    fn for_each_yield(&self, per_set: Option<f64>, f: &mut dyn FnMut(&Filter, f64)) {
+        self.0.for_each_yield(Some(per_set.unwrap_or(self.1)), f)
+    }
+}
+
+impl BoxedOutputs for Rc<dyn Outputs> {
+    fn and(self, other: Self) -> Self { Rc::new(AndOutputs(self, other)) }
+    fn or(self, other: Self) -> Self { Rc::new(OrOutputs(self, other)) }
+
     fn not(self) -> Self {
         Rc::new(move |factory: &_| match self.get_priority(factory) {
             Some(_) => None,
@@ -43,32 +145,60 @@ impl BoxedOutputs for Rc<dyn Outputs> {
         })
     }
 
-    fn map_priority(self, f: impl Fn(&Factory, f64) -> f64 + 'static) -> Self {
-        Rc::new(move |factory: &_| self.get_priority(factory).map(|x| f(factory, x)))
-    }
+    fn map_priority(self, f: impl Fn(&Factory, f64) -> f64 + 'static) -> Self { Rc::new(MapPriority(self, f)) }
+    fn per_set(self, n: f64) -> Self { Rc::new(PerSet(self, n)) }
 }
 
 pub fn ignore_outputs(priority: f64) -> Rc<dyn Outputs> { Rc::new(move |_: &_| Some(priority)) }
 
 pub struct Output {
     pub item: Filter,
-    pub n_wanted: i32,
+    pub n_wanted: i64,
+    // Compare against the sum of all matching items instead of the most stocked one.
+    pub aggregate: bool,
 }
 
 impl Output {
-    pub fn new(item: Filter, n_wanted: i32) -> Rc<dyn Outputs> { Rc::new(Self { item, n_wanted }) }
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(item: Filter, n_wanted: i64) -> Rc<dyn Outputs> { Rc::new(Self { item, n_wanted, aggregate: false }) }
+    pub fn new_aggregate(item: Filter, n_wanted: i64) -> Rc<dyn Outputs> {
+        Rc::new(Self { item, n_wanted, aggregate: true })
+    }
+
+    pub fn get_n_wanted(&self, factory: &Factory) -> i64 {
+        factory.get_override(OverrideKind::Target, &self.item).unwrap_or(self.n_wanted)
+    }
+
+    pub fn get_n_stored(&self, factory: &Factory) -> i64 {
+        if self.aggregate {
+            factory.search_n_stored_all(&self.item)
+        } else {
+            factory.search_n_stored(&self.item)
+        }
+    }
 }
 
 impl Outputs for Output {
     fn get_priority(&self, factory: &Factory) -> Option<f64> {
-        let n_stored = factory.search_n_stored(&self.item);
-        let n_needed = self.n_wanted - n_stored;
+        let n_wanted = self.get_n_wanted(factory);
+        let n_needed = n_wanted - self.get_n_stored(factory) - factory.get_in_flight(&self.item);
         if n_needed > 0 {
-            Some(n_needed as f64 / self.n_wanted as f64)
+            Some(n_needed as f64 / n_wanted as f64)
         } else {
             None
         }
     }
+
+    fn add_in_flight(&self, factory: &mut Factory, n_sets: i64, per_set: Option<f64>, recipe: RecipeId) {
+        let per_set = self.get_per_set(factory, per_set, recipe);
+        factory.add_in_flight(&self.item, (n_sets as f64 * per_set).round() as i64)
+    }
+
+    fn get_per_set(&self, factory: &Factory, per_set: Option<f64>, recipe: RecipeId) -> f64 {
+        factory.get_yield(recipe, &self.item).or(per_set).unwrap_or(1.)
+    }
+
+    fn for_each_filter(&self, f: &mut dyn FnMut(&Filter)) { f(&self.item) }
 }
 
 pub struct FluidOutput {
@@ -77,47 +207,167 @@ pub struct FluidOutput {
 }
 
 impl FluidOutput {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(fluid: LocalStr, n_wanted: i64) -> Rc<dyn Outputs> { Rc::new(Self { fluid, n_wanted }) }
+
+    pub fn get_n_wanted(&self, factory: &Factory) -> i64 {
+        factory.get_override(OverrideKind::FluidTarget, &self.fluid).unwrap_or(self.n_wanted)
+    }
 }
 
 impl Outputs for FluidOutput {
     fn get_priority(&self, factory: &Factory) -> Option<f64> {
-        let n_stored = factory.search_n_fluid(&self.fluid);
-        let n_needed = self.n_wanted - n_stored;
+        let n_wanted = self.get_n_wanted(factory);
+        let n_needed = n_wanted - factory.search_n_fluid(&self.fluid);
         if n_needed > 0 {
-            Some(n_needed as f64 / self.n_wanted as f64)
+            Some(n_needed as f64 / n_wanted as f64)
         } else {
             None
         }
     }
 }
 
+// Starts wanting once the stock drops below `low` and keeps wanting until it reaches `output.n_wanted`.
+pub struct HysteresisOutput {
+    pub output: Output,
+    pub low: i64,
+    active: Cell<bool>,
+}
+
+impl HysteresisOutput {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(item: Filter, low: i64, high: i64) -> Rc<dyn Outputs> {
+        Rc::new(Self { output: Output { item, n_wanted: high, aggregate: false }, low, active: Cell::new(false) })
+    }
+    pub fn new_aggregate(item: Filter, low: i64, high: i64) -> Rc<dyn Outputs> {
+        Rc::new(Self { output: Output { item, n_wanted: high, aggregate: true }, low, active: Cell::new(false) })
+    }
+}
+
+fn update_hysteresis(active: &Cell<bool>, n_stored: i64, low: i64, high: i64) -> bool {
+    if n_stored < low {
+        active.set(true)
+    } else if n_stored >= high {
+        active.set(false)
+    }
+    active.get()
+}
+
+impl Outputs for HysteresisOutput {
+    fn get_priority(&self, factory: &Factory) -> Option<f64> {
+        let n_stored = self.output.get_n_stored(factory);
+        if update_hysteresis(&self.active, n_stored, self.low, self.output.get_n_wanted(factory)) {
+            self.output.get_priority(factory)
+        } else {
+            None
+        }
+    }
+
+    fn add_in_flight(&self, factory: &mut Factory, n_sets: i64, per_set: Option<f64>, recipe: RecipeId) {
+        self.output.add_in_flight(factory, n_sets, per_set, recipe)
+    }
+
+    fn get_per_set(&self, factory: &Factory, per_set: Option<f64>, recipe: RecipeId) -> f64 {
+        self.output.get_per_set(factory, per_set, recipe)
+    }
+
+    fn for_each_filter(&self, f: &mut dyn FnMut(&Filter)) { self.output.for_each_filter(f) }
+}
+
+pub struct FluidHysteresisOutput {
+    pub output: FluidOutput,
+    pub low: i64,
+    active: Cell<bool>,
+}
+
+impl FluidHysteresisOutput {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(fluid: LocalStr, low: i64, high: i64) -> Rc<dyn Outputs> {
+        Rc::new(Self { output: FluidOutput { fluid, n_wanted: high }, low, active: Cell::new(false) })
+    }
+}
+
+impl Outputs for FluidHysteresisOutput {
+    fn get_priority(&self, factory: &Factory) -> Option<f64> {
+        let n_stored = factory.search_n_fluid(&self.output.fluid);
+        if update_hysteresis(&self.active, n_stored, self.low, self.output.get_n_wanted(factory)) {
+            self.output.get_priority(factory)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Alternative {
+    pub item: Filter,
+    pub allow_backup: bool,
+    pub extra_backup: i64,
+}
+
 pub trait Input {
     fn get_item(&self) -> &Filter;
-    fn get_size(&self) -> i32;
+    fn get_size(&self) -> i64;
     fn get_allow_backup(&self) -> bool;
-    fn get_extra_backup(&self) -> i32;
+    fn get_extra_backup(&self) -> i64;
+    fn get_alternatives(&self) -> &Vec<Alternative>;
+    // After `or`, these apply to the alternative just added.
     fn allow_backup(self) -> Self;
-    fn extra_backup(self, size: i32) -> Self;
+    fn extra_backup(self, size: i64) -> Self;
+    // Adds a filter to fall back to, in order, when the previous ones don't have enough for a set.
+    fn or(self, item: Filter) -> Self;
+    // Whether one set may be drawn from several matching items when no single one has enough.
+    fn get_mixed(&self) -> bool { false }
+
+    // (filter, allow_backup, extra_backup) in order of preference.
+    fn get_choices(&self) -> impl Iterator<Item = (&Filter, bool, i64)> {
+        once((self.get_item(), self.get_allow_backup(), self.get_extra_backup()))
+            .chain(self.get_alternatives().iter().map(|x| (&x.item, x.allow_backup, x.extra_backup)))
+    }
+
+    fn accepts(&self, item: &Item) -> bool { self.get_choices().any(|(filter, _, _)| filter.apply(item)) }
 }
 
 macro_rules! impl_input {
     ($i:ident) => {
         impl Input for $i {
-            fn get_item(&self) -> &Filter { &self.item }
-            fn get_size(&self) -> i32 { self.size }
-            fn get_allow_backup(&self) -> bool { self.allow_backup }
-            fn get_extra_backup(&self) -> i32 { self.extra_backup }
-
-            fn allow_backup(mut self) -> Self {
-                self.allow_backup = true;
-                self
+            impl_input!(@common);
+        }
+    };
+    ($i:ident, mixed) => {
+        impl Input for $i {
+            impl_input!(@common);
+            fn get_mixed(&self) -> bool { self.mixed }
+        }
+    };
+    (@common) => {
+        fn get_item(&self) -> &Filter { &self.item }
+        fn get_size(&self) -> i64 { self.size }
+        fn get_allow_backup(&self) -> bool { self.allow_backup }
+        fn get_extra_backup(&self) -> i64 { self.extra_backup }
+        fn get_alternatives(&self) -> &Vec<Alternative> { &self.alternatives }
+
+        fn allow_backup(mut self) -> Self {
+            if let Some(alternative) = self.alternatives.last_mut() {
+                alternative.allow_backup = true
+            } else {
+                self.allow_backup = true
             }
+            self
+        }
 
-            fn extra_backup(mut self, size: i32) -> Self {
-                self.extra_backup += size;
-                self
+        fn extra_backup(mut self, size: i64) -> Self {
+            if let Some(alternative) = self.alternatives.last_mut() {
+                alternative.extra_backup += size
+            } else {
+                self.extra_backup += size
             }
+            self
+        }
+
+        fn or(mut self, item: Filter) -> Self {
+            self.alternatives.push(Alternative { item, allow_backup: false, extra_backup: 0 });
+            self
         }
     };
 }
@@ -126,80 +376,330 @@ pub trait Recipe: Clone {
     type In: Input;
     fn get_outputs(&self) -> &dyn Outputs;
     fn get_inputs(&self) -> &Vec<Self::In>;
+    fn get_min_sets(&self) -> i64 { 1 }
+    fn get_max_wait(&self) -> Option<Duration> { None }
 }
 
 macro_rules! impl_recipe {
     ($r:ident, $i:ident) => {
         impl Recipe for $r {
-            type In = $i;
-            fn get_outputs(&self) -> &dyn Outputs { &*self.outputs }
-            fn get_inputs(&self) -> &Vec<$i> { &self.inputs }
+            impl_recipe!(@common $i);
         }
     };
+    ($r:ident, $i:ident, batched) => {
+        impl Recipe for $r {
+            impl_recipe!(@common $i);
+            fn get_min_sets(&self) -> i64 { self.min_sets }
+            fn get_max_wait(&self) -> Option<Duration> { self.max_wait }
+        }
+    };
+    (@common $i:ident) => {
+        type In = $i;
+        fn get_outputs(&self) -> &dyn Outputs { &*self.outputs }
+        fn get_inputs(&self) -> &Vec<$i> { &self.inputs }
+    };
 }
 
+// (item, size) parts making up a mixed input.
+pub type MixedParts = Vec<(Rc<Item>, i64)>;
+
 pub struct ResolvedInputs {
-    pub n_sets: i32,
-    pub priority: i32,
+    pub n_sets: i64,
+    pub priority: i64,
     pub items: Vec<Rc<Item>>,
+    // If set, n_sets is 1 and each input is made up of these (item, size) parts.
+    pub mixed: Option<Vec<MixedParts>>,
 }
 
 struct InputInfo {
-    n_available: i32,
-    n_needed: i32,
+    n_available: i64,
+    n_needed: i64,
 }
 
 pub fn resolve_inputs(factory: &Factory, recipe: &impl Recipe) -> Option<ResolvedInputs> {
+    let inputs = resolve_inputs_unbatched(factory, recipe);
+    let n_sets = inputs.as_ref().map_or(0, |inputs| inputs.n_sets);
+    if is_batch_ready(factory, recipe, n_sets) {
+        inputs
+    } else {
+        None
+    }
+}
+
+// Withholds inputs until `min_sets` sets are available, or until `max_wait` has passed since any became available.
+// Processes call this again with the sets they'd actually load; waits not renewed in a cycle are dropped.
+pub fn is_batch_ready(factory: &Factory, recipe: &impl Recipe, n_sets: i64) -> bool {
+    if n_sets <= 0 {
+        return false;
+    } else if n_sets >= recipe.get_min_sets() {
+        return true;
+    }
+    let Some(max_wait) = recipe.get_max_wait() else { return false };
+    let key = recipe_key(recipe);
+    factory.batch_seen.borrow_mut().insert(key);
+    let now = Instant::now();
+    now.duration_since(*factory.batch_waits.borrow_mut().entry(key).or_insert(now)) >= max_wait
+}
+
+pub fn resolve_inputs_unbatched(factory: &Factory, recipe: &impl Recipe) -> Option<ResolvedInputs> {
     let mut items = Vec::new();
     items.reserve(recipe.get_inputs().len());
     let mut infos = FnvHashMap::<&Rc<Item>, InputInfo>::default();
-    let mut max_size_bound = i32::MAX;
+    let mut max_size_bound = i64::MAX;
     for input in recipe.get_inputs() {
-        if let Some((item, item_info)) = factory.search_item(input.get_item()) {
-            items.push(item.clone());
-            match infos.entry(item) {
-                Entry::Occupied(input_info) => input_info.into_mut().n_needed += input.get_size(),
-                Entry::Vacant(input_info) => {
-                    input_info.insert(InputInfo {
-                        // Note: backup params are considered for only the first input of the same item.
-                        n_available: (item_info.borrow())
-                            .get_availability(input.get_allow_backup(), input.get_extra_backup()),
-                        n_needed: input.get_size(),
-                    });
-                }
+        // Take the first alternative with enough for a set, or else the first one present at all.
+        let mut chosen = None;
+        for (filter, allow_backup, extra_backup) in input.get_choices() {
+            let Some((item, item_info)) = factory.search_item(filter) else { continue };
+            let (n_available, n_needed) = if let Some(input_info) = infos.get(item) {
+                // Note: backup params are considered for only the first input of the same item.
+                (input_info.n_available, input_info.n_needed + input.get_size())
+            } else {
+                let n_available = (item_info.borrow())
+                    .get_availability(allow_backup, extra_backup)
+                    .min(factory.get_grant(recipe_key(recipe), item))
+                    .min(factory.get_budget(item));
+                (n_available, input.get_size())
+            };
+            if n_available >= n_needed {
+                chosen = Some((item, n_available));
+                break;
+            } else if chosen.is_none() {
+                chosen = Some((item, n_available))
+            }
+        }
+        let (item, n_available) = chosen?;
+        items.push(item.clone());
+        match infos.entry(item) {
+            Entry::Occupied(input_info) => input_info.into_mut().n_needed += input.get_size(),
+            Entry::Vacant(input_info) => {
+                input_info.insert(InputInfo { n_available, n_needed: input.get_size() });
             }
-            max_size_bound = max_size_bound.min(item.max_size / input.get_size());
-        } else {
-            return None;
         }
+        max_size_bound = max_size_bound.min(item.max_size / input.get_size());
     }
-    let mut availability_bound = i32::MAX;
-    for (_, input_info) in infos.into_iter() {
+    let mut availability_bound = i64::MAX;
+    let mut budget_needed = FnvHashMap::<usize, i64>::default();
+    for (item, input_info) in infos.into_iter() {
         let limit = input_info.n_available / input_info.n_needed;
-        availability_bound = availability_bound.min(limit)
+        availability_bound = availability_bound.min(limit);
+        for i_budget in factory.covering_budgets(item) {
+            *budget_needed.entry(i_budget).or_default() += input_info.n_needed
+        }
+    }
+    for (i_budget, n_needed) in budget_needed {
+        availability_bound = availability_bound.min(factory.budget_left(i_budget) / n_needed)
     }
     let n_sets = max_size_bound.min(availability_bound);
     if n_sets > 0 {
-        Some(ResolvedInputs { n_sets, priority: availability_bound, items })
+        Some(ResolvedInputs { n_sets, priority: availability_bound, items, mixed: None })
+    } else if recipe.get_inputs().iter().any(|input| input.get_mixed()) {
+        resolve_mixed_inputs(factory, recipe)
     } else {
         None
     }
 }
 
+fn resolve_mixed_inputs(factory: &Factory, recipe: &impl Recipe) -> Option<ResolvedInputs> {
+    // Already taken by earlier inputs, as each input applies its own backup params to what's available.
+    let mut taken = FnvHashMap::<&Rc<Item>, i64>::default();
+    let mut budgets_left = FnvHashMap::<usize, i64>::default();
+    let mut items = Vec::new();
+    let mut mixed = Vec::new();
+    for input in recipe.get_inputs() {
+        // Take the first alternative that can make up a set.
+        let mut chosen = None;
+        for (filter, allow_backup, extra_backup) in input.get_choices() {
+            let candidates = if input.get_mixed() {
+                factory.search_items(filter)
+            } else {
+                Vec::from_iter(factory.search_item(filter))
+            };
+            let mut taken = taken.clone();
+            let mut budgets_left = budgets_left.clone();
+            let mut parts = Vec::new();
+            let mut n_needed = input.get_size();
+            for (item, info) in candidates {
+                let n_available = info.borrow().get_availability(allow_backup, extra_backup);
+                let n_available =
+                    n_available.min(factory.get_grant(recipe_key(recipe), item)).min(factory.get_budget(item));
+                let n_taken_before = taken.entry(item).or_default();
+                let mut n_taken = n_needed.min(n_available - *n_taken_before);
+                for i_budget in factory.covering_budgets(item) {
+                    n_taken =
+                        n_taken.min(*budgets_left.entry(i_budget).or_insert_with(|| factory.budget_left(i_budget)))
+                }
+                if n_taken > 0 {
+                    *n_taken_before += n_taken;
+                    n_needed -= n_taken;
+                    for i_budget in factory.covering_budgets(item) {
+                        *budgets_left.get_mut(&i_budget).unwrap() -= n_taken
+                    }
+                    parts.push((item.clone(), n_taken))
+                }
+                if n_needed <= 0 {
+                    chosen = Some((parts, taken, budgets_left));
+                    break;
+                }
+            }
+            if chosen.is_some() {
+                break;
+            }
+        }
+        let parts;
+        (parts, taken, budgets_left) = chosen?;
+        items.push(parts[0].0.clone());
+        mixed.push(parts)
+    }
+    Some(ResolvedInputs { n_sets: 1, priority: 1, items, mixed: Some(mixed) })
+}
+
+// Identifies a recipe across processes for the duration of the program.
+pub fn recipe_key(recipe: &impl Recipe) -> *const () { recipe as *const _ as *const () }
+
+// An item a recipe would consume this cycle, for arbitration between processes.
+pub struct Proposal {
+    pub recipe: *const (),
+    pub item: Rc<Item>,
+    pub size: i64,
+    pub priority: f64,
+    // Backup params of the input taking the item, which bound what it may be granted.
+    pub allow_backup: bool,
+    pub extra_backup: i64,
+}
+
+// Proposes the items of demands as clamped to what the process would actually load.
+pub fn propose_demands(recipes: &[impl Recipe], demands: Vec<Demand>) -> Vec<Proposal> {
+    let mut result = Vec::new();
+    for Demand { i_recipe, inputs, priority } in demands {
+        let recipe = &recipes[i_recipe];
+        let mut sizes = FnvHashMap::<Rc<Item>, i64>::default();
+        if let Some(mixed) = inputs.mixed {
+            for (item, size) in mixed.into_iter().flatten() {
+                *sizes.entry(item).or_default() += size
+            }
+        } else {
+            for (item, input) in inputs.items.into_iter().zip(recipe.get_inputs()) {
+                *sizes.entry(item).or_default() += inputs.n_sets * input.get_size()
+            }
+        }
+        for (item, size) in sizes {
+            // Note: as in resolving, backup params are taken from the first input of the same item.
+            let choice = recipe.get_inputs().iter().flat_map(|x| x.get_choices()).find(|(x, _, _)| x.apply(&item));
+            let (allow_backup, extra_backup) = choice.map_or((false, 0), |(_, allow, extra)| (allow, extra));
+            result.push(Proposal { recipe: recipe_key(recipe), item, size, priority, allow_backup, extra_backup })
+        }
+    }
+    result
+}
+
+// Filters a recipe consumes and produces with their amounts per set, for analyzing the recipe graph.
+pub struct RecipeLinks {
+    pub inputs: Vec<(Filter, i64)>,
+    pub outputs: Vec<(Filter, f64)>,
+}
+
+pub fn link_recipes(recipes: &[impl Recipe]) -> Vec<RecipeLinks> {
+    Vec::from_iter(recipes.iter().map(|recipe| {
+        let inputs = recipe
+            .get_inputs()
+            .iter()
+            .flat_map(|input| input.get_choices().map(|(x, _, _)| (x.clone(), input.get_size())));
+        let mut outputs = Vec::new();
+        recipe.get_outputs().for_each_yield(None, &mut |x, per_set| outputs.push((x.clone(), per_set)));
+        RecipeLinks { inputs: inputs.collect(), outputs }
+    }))
+}
+
+pub fn produces(recipe: &impl Recipe, item: &Item) -> bool {
+    let mut result = false;
+    recipe.get_outputs().for_each_filter(&mut |x| result |= x.apply(item));
+    result
+}
+
 pub struct Demand {
     pub i_recipe: usize,
     pub inputs: ResolvedInputs,
     pub priority: f64,
 }
 
-pub fn compute_demands(factory: &Factory, recipes: &[impl Recipe]) -> Vec<Demand> {
+pub fn compute_demands(
+    factory: &Factory,
+    name: &str,
+    recipes: &[impl Recipe],
+    policy: &dyn DemandPolicy,
+) -> Vec<Demand> {
     let mut result = Vec::new();
     for (i_recipe, recipe) in recipes.iter().enumerate() {
         let Some(mut priority) = recipe.get_outputs().get_priority(factory) else { continue };
         let Some(inputs) = resolve_inputs(factory, recipe) else { continue };
-        priority *= inputs.priority as f64;
+        // Weighs the sets available by what they're expected to yield.
+        priority *= inputs.priority as f64 * recipe.get_outputs().get_per_set(factory, None, (name, i_recipe));
         result.push(Demand { i_recipe, inputs, priority })
     }
-    result.sort_by(|x: &Demand, y: &Demand| x.priority.partial_cmp(&y.priority).unwrap().reverse());
+    policy.order(&mut result);
     result
 }
+
+// Decides which of the currently needed recipes a process tries first.
+// `order` may be called more than once per cycle, so it shouldn't count calls.
+pub trait DemandPolicy {
+    fn order(&self, demands: &mut Vec<Demand>);
+    fn on_executed(&self, _i_recipe: usize) {}
+}
+
+fn sort_by_priority(demands: &mut [Demand]) {
+    demands.sort_by(|x: &Demand, y: &Demand| x.priority.total_cmp(&y.priority).reverse())
+}
+
+// The most needed and most available recipe first.
+pub struct ByPriority;
+pub fn by_priority() -> Box<dyn DemandPolicy> { Box::new(ByPriority) }
+impl DemandPolicy for ByPriority {
+    fn order(&self, demands: &mut Vec<Demand>) { sort_by_priority(demands) }
+}
+
+// Recipes in the order they're configured.
+pub struct FixedOrder;
+pub fn fixed_order() -> Box<dyn DemandPolicy> { Box::new(FixedOrder) }
+impl DemandPolicy for FixedOrder {
+    fn order(&self, demands: &mut Vec<Demand>) { demands.sort_by_key(|x| x.i_recipe) }
+}
+
+// Recipes in configured order, starting after the last executed one.
+#[derive(Default)]
+pub struct RoundRobin {
+    last: Cell<Option<usize>>,
+}
+
+pub fn round_robin() -> Box<dyn DemandPolicy> { Box::new(RoundRobin::default()) }
+impl DemandPolicy for RoundRobin {
+    fn order(&self, demands: &mut Vec<Demand>) {
+        let last = self.last.get();
+        demands.sort_by_key(|x| (last.is_some_and(|last| x.i_recipe <= last), x.i_recipe))
+    }
+
+    fn on_executed(&self, i_recipe: usize) { self.last.set(Some(i_recipe)) }
+}
+
+// By priority, but each recipe's priority grows by `rate` per second it has been needed without being executed.
+pub struct Aging {
+    pub rate: f64,
+    since: RefCell<FnvHashMap<usize, Instant>>,
+}
+
+pub fn aging(rate: f64) -> Box<dyn DemandPolicy> { Box::new(Aging { rate, since: <_>::default() }) }
+impl DemandPolicy for Aging {
+    fn order(&self, demands: &mut Vec<Demand>) {
+        let now = Instant::now();
+        let mut since = self.since.borrow_mut();
+        since.retain(|i_recipe, _| demands.iter().any(|x| x.i_recipe == *i_recipe));
+        for demand in demands.iter_mut() {
+            let since = *since.entry(demand.i_recipe).or_insert(now);
+            demand.priority += self.rate * (now - since).as_secs_f64()
+        }
+        sort_by_priority(demands)
+    }
+
+    fn on_executed(&self, i_recipe: usize) { self.since.borrow_mut().remove(&i_recipe); }
+}
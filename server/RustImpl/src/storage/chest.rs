@@ -1,11 +1,13 @@
-use super::super::access::InvAccess;
-use super::super::action::{ActionFuture, Call, List};
+use super::super::access::{Access, ItemAccess};
+use super::super::action::ActionFuture;
 use super::super::factory::Factory;
 use super::super::item::{Item, ItemStack};
 use super::super::util::{alive, spawn};
-use super::{DepositResult, Extractor, IntoStorage, Provider, Storage};
+use super::{
+    DepositResult, DepositRule, ExtractStrategy, Extractor, IntoStorage, PartialStack, Provider, Storage, StorageUsage,
+};
 use abort_on_drop::ChildTask;
-use flexstr::{local_str, LocalStr};
+use flexstr::{local_fmt, LocalStr};
 use std::{
     cell::RefCell,
     cmp::min,
@@ -13,7 +15,10 @@ use std::{
 };
 
 pub struct ChestConfig {
-    pub accesses: Vec<InvAccess>,
+    pub accesses: Vec<ItemAccess>,
+    pub deposit: DepositRule,
+    pub extract_priority: i32,
+    pub extract_strategy: ExtractStrategy,
 }
 
 pub struct ChestStorage {
@@ -21,6 +26,8 @@ pub struct ChestStorage {
     config: ChestConfig,
     factory: Weak<RefCell<Factory>>,
     stacks: Vec<Option<ItemStack>>,
+    // Those given to providers, so that partial stacks can be reserved through them.
+    extractors: Vec<Option<Rc<dyn Extractor>>>,
     inv_slot_to_deposit: usize,
 }
 
@@ -38,6 +45,7 @@ impl IntoStorage for ChestConfig {
                 config: self,
                 factory: factory.weak.clone(),
                 stacks: Vec::new(),
+                extractors: Vec::new(),
                 inv_slot_to_deposit: 0,
             })
         })
@@ -48,30 +56,61 @@ impl Storage for ChestStorage {
     fn update(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
         let server = factory.borrow_server();
         let access = server.load_balance(&self.config.accesses).1;
-        let action = ActionFuture::from(List { addr: access.addr.clone(), side: access.inv_side });
-        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        let action = ActionFuture::from(access.list(false));
+        server.enqueue_request_group(access.get_client(), vec![action.clone().into()]);
         let weak = self.weak.clone();
         spawn(async move {
-            let stacks = action.await?;
+            let mut stacks = action.await?;
             alive_mut!(weak, this);
-            this.stacks = stacks;
             upgrade_mut!(this.factory, factory);
+            factory.canonicalize_stacks(&mut stacks);
+            this.stacks = stacks;
+            let mut extractors = Vec::new();
             for (inv_slot, stack) in this.stacks.iter().enumerate() {
+                extractors.push(None);
                 if let Some(stack) = stack {
+                    let extractor: Rc<dyn Extractor> = Rc::new(ChestExtractor { weak: weak.clone(), inv_slot });
+                    extractors[inv_slot] = Some(extractor.clone());
                     factory.register_stored_item(stack.item.clone()).provide(Provider {
-                        priority: -stack.size,
+                        storage_priority: this.config.extract_priority,
+                        priority: match this.config.extract_strategy {
+                            ExtractStrategy::SmallestFirst => -stack.size,
+                            ExtractStrategy::LargestFirst => stack.size,
+                        },
                         n_provided: stack.size.into(),
-                        extractor: Rc::new(ChestExtractor { weak: weak.clone(), inv_slot }),
+                        extractor,
                     });
                 }
             }
+            this.extractors = extractors;
             Ok(())
         })
     }
 
-    fn cleanup(&mut self) { self.stacks.clear() }
+    fn cleanup(&mut self) {
+        self.stacks.clear();
+        self.extractors.clear()
+    }
+    fn describe(&self) -> LocalStr { local_fmt!("chest {}", self.config.accesses[0].get_addr()) }
+
+    fn get_usage(&self) -> Option<StorageUsage> {
+        Some(StorageUsage { n_used: self.stacks.iter().flatten().count(), n_total: self.stacks.len() })
+    }
+
+    fn get_partial_stacks(&self) -> Vec<PartialStack> {
+        let stacks = self
+            .stacks
+            .iter()
+            .zip(&self.extractors)
+            .filter_map(|(stack, extractor)| Some((stack.as_ref()?, extractor.as_ref()?)));
+        Vec::from_iter(stacks.filter(|(stack, _)| stack.size < stack.item.max_size).map(|(stack, extractor)| {
+            PartialStack { item: stack.item.clone(), size: stack.size, extractor: extractor.clone() }
+        }))
+    }
+
+    fn get_deposit_rule(&self) -> &DepositRule { &self.config.deposit }
 
-    fn deposit_priority(&mut self, item: &Rc<Item>) -> Option<i32> {
+    fn deposit_priority(&mut self, item: &Rc<Item>) -> Option<i64> {
         let mut empty_slot = None;
         let mut size_of_best_slot = None;
         for (inv_slot, stack) in self.stacks.iter().enumerate() {
@@ -92,7 +131,7 @@ impl Storage for ChestStorage {
         size_of_best_slot.or_else(|| {
             empty_slot.map(|x| {
                 self.inv_slot_to_deposit = x;
-                i32::MIN + 1
+                i64::MIN + 1
             })
         })
     }
@@ -110,41 +149,21 @@ impl Storage for ChestStorage {
         }
         let server = factory.borrow_server();
         let access = server.load_balance(&self.config.accesses).1;
-        let action = ActionFuture::from(Call {
-            addr: access.addr.clone(),
-            func: local_str!("transferItem"),
-            args: vec![
-                access.bus_side.into(),
-                access.inv_side.into(),
-                n_deposited.into(),
-                (bus_slot + 1).into(),
-                (inv_slot + 1).into(),
-            ],
-        });
-        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        let action = ActionFuture::from(access.from_bus(n_deposited, bus_slot, Some(inv_slot)));
+        server.enqueue_request_group(access.get_client(), vec![action.clone().into()]);
         let task = spawn(async move { action.await.map(|_| ()) });
         DepositResult { n_deposited, task }
     }
 }
 
 impl Extractor for ChestExtractor {
-    fn extract(&self, factory: &Factory, size: i32, bus_slot: usize) -> ChildTask<Result<(), LocalStr>> {
+    fn extract(&self, factory: &Factory, size: i64, bus_slot: usize) -> ChildTask<Result<(), LocalStr>> {
         let inv_slot = self.inv_slot;
         let server = factory.borrow_server();
         upgrade!(self.weak, this);
         let access = server.load_balance(&this.config.accesses).1;
-        let action = ActionFuture::from(Call {
-            addr: access.addr.clone(),
-            func: local_str!("transferItem"),
-            args: vec![
-                access.inv_side.into(),
-                access.bus_side.into(),
-                size.into(),
-                (inv_slot + 1).into(),
-                (bus_slot + 1).into(),
-            ],
-        });
-        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        let action = ActionFuture::from(access.to_bus(size, inv_slot, bus_slot));
+        server.enqueue_request_group(access.get_client(), vec![action.clone().into()]);
         let weak = self.weak.clone();
         spawn(async move {
             action.await?;
@@ -1,25 +1,32 @@
-use super::super::access::InvAccess;
-use super::super::action::{ActionFuture, Call, List};
+use super::super::access::{Access, ItemAccess};
+use super::super::action::{ActionFuture, Print};
 use super::super::factory::Factory;
 use super::super::item::{Filter, Item, ItemStack};
 use super::super::util::{alive, spawn};
-use super::{DepositResult, Extractor, IntoStorage, Provider, Storage};
+use super::{DepositResult, DepositRule, Extractor, IntoStorage, Provider, Storage, StorageUsage};
 use abort_on_drop::ChildTask;
-use flexstr::{local_str, LocalStr};
+use flexstr::{local_fmt, LocalStr};
 use std::{
     cell::RefCell,
     rc::{Rc, Weak},
 };
 
 pub struct DrawerConfig {
-    pub accesses: Vec<InvAccess>,
+    pub accesses: Vec<ItemAccess>,
     pub filters: Vec<Filter>,
+    // Also accept whatever the drawers are listed to hold, including empty locked ones.
+    pub discover: bool,
+    pub deposit: DepositRule,
+    pub extract_priority: i32,
 }
 
 pub struct DrawerStorage {
     weak: Weak<RefCell<DrawerStorage>>,
     config: DrawerConfig,
     factory: Weak<RefCell<Factory>>,
+    usage: Option<StorageUsage>,
+    // By slot, as of the last update when `discover` is set.
+    discovered: Vec<Option<Rc<Item>>>,
 }
 
 struct DrawerExtractor {
@@ -31,7 +38,13 @@ impl IntoStorage for DrawerConfig {
     type Output = DrawerStorage;
     fn into_storage(self, factory: &Factory) -> Rc<RefCell<Self::Output>> {
         Rc::new_cyclic(|weak| {
-            RefCell::new(Self::Output { weak: weak.clone(), config: self, factory: factory.weak.clone() })
+            RefCell::new(Self::Output {
+                weak: weak.clone(),
+                config: self,
+                factory: factory.weak.clone(),
+                usage: None,
+                discovered: Vec::new(),
+            })
         })
     }
 }
@@ -40,17 +53,42 @@ impl Storage for DrawerStorage {
     fn update(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
         let server = factory.borrow_server();
         let access = server.load_balance(&self.config.accesses).1;
-        let action = ActionFuture::from(List { addr: access.addr.clone(), side: access.inv_side });
-        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        let action = ActionFuture::from(access.list(self.config.discover));
+        server.enqueue_request_group(access.get_client(), vec![action.clone().into()]);
         let weak = self.weak.clone();
         spawn(async move {
-            let stacks = action.await?;
-            alive!(weak, this);
+            let mut stacks = action.await?;
+            alive_mut!(weak, this);
+            let n_used = stacks.iter().flatten().filter(|x| x.size > 0).count();
+            this.usage = Some(StorageUsage { n_used, n_total: stacks.len() });
             upgrade_mut!(this.factory, factory);
+            factory.canonicalize_stacks(&mut stacks);
+            if this.config.discover {
+                let discovered = Vec::from_iter(stacks.iter().map(|x| x.as_ref().map(|x| x.item.clone())));
+                for (inv_slot, (old, new)) in this.discovered.iter().zip(&discovered).enumerate() {
+                    if let (Some(old), Some(new)) = (old, new) {
+                        if old != new {
+                            factory.log(Print {
+                                text: local_fmt!(
+                                    "drawer {} slot {}: {} -> {}",
+                                    this.config.accesses[0].get_addr(),
+                                    inv_slot + 1,
+                                    old.label,
+                                    new.label
+                                ),
+                                color: 0xFF0000,
+                                beep: Some(880.0),
+                            })
+                        }
+                    }
+                }
+                this.discovered = discovered
+            }
             for (inv_slot, stack) in stacks.into_iter().enumerate() {
-                if let Some(stack) = stack {
+                if let Some(stack) = stack.filter(|x| x.size > 0) {
                     factory.register_stored_item(stack.item).provide(Provider {
-                        priority: i32::MIN,
+                        storage_priority: this.config.extract_priority,
+                        priority: i64::MIN,
                         n_provided: stack.size.into(),
                         extractor: Rc::new(DrawerExtractor { weak: weak.clone(), inv_slot }),
                     });
@@ -61,13 +99,20 @@ impl Storage for DrawerStorage {
     }
 
     fn cleanup(&mut self) {}
+    fn describe(&self) -> LocalStr { local_fmt!("drawer {}", self.config.accesses[0].get_addr()) }
+    fn get_usage(&self) -> Option<StorageUsage> { self.usage }
 
-    fn deposit_priority(&mut self, item: &Rc<Item>) -> Option<i32> {
+    fn get_deposit_rule(&self) -> &DepositRule { &self.config.deposit }
+
+    fn deposit_priority(&mut self, item: &Rc<Item>) -> Option<i64> {
         for filter in &self.config.filters {
             if filter.apply(item) {
-                return Some(i32::MAX);
+                return Some(i64::MAX);
             }
         }
+        if self.discovered.iter().flatten().any(|x| x == item) {
+            return Some(i64::MAX);
+        }
         None
     }
 
@@ -75,34 +120,20 @@ impl Storage for DrawerStorage {
         let n_deposited = stack.size;
         let server = factory.borrow_server();
         let access = server.load_balance(&self.config.accesses).1;
-        let action = ActionFuture::from(Call {
-            addr: access.addr.clone(),
-            func: local_str!("transferItem"),
-            args: vec![access.bus_side.into(), access.inv_side.into(), n_deposited.into(), (bus_slot + 1).into()],
-        });
-        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        let action = ActionFuture::from(access.from_bus(n_deposited, bus_slot, None));
+        server.enqueue_request_group(access.get_client(), vec![action.clone().into()]);
         let task = spawn(async move { action.await.map(|_| ()) });
         DepositResult { n_deposited, task }
     }
 }
 
 impl Extractor for DrawerExtractor {
-    fn extract(&self, factory: &Factory, size: i32, bus_slot: usize) -> ChildTask<Result<(), LocalStr>> {
+    fn extract(&self, factory: &Factory, size: i64, bus_slot: usize) -> ChildTask<Result<(), LocalStr>> {
         upgrade!(self.weak, this);
         let server = factory.borrow_server();
         let access = server.load_balance(&this.config.accesses).1;
-        let action = ActionFuture::from(Call {
-            addr: access.addr.clone(),
-            func: local_str!("transferItem"),
-            args: vec![
-                access.inv_side.into(),
-                access.bus_side.into(),
-                size.into(),
-                (self.inv_slot + 1).into(),
-                (bus_slot + 1).into(),
-            ],
-        });
-        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        let action = ActionFuture::from(access.to_bus(size, self.inv_slot, bus_slot));
+        server.enqueue_request_group(access.get_client(), vec![action.clone().into()]);
         spawn(async move { action.await.map(|_| ()) })
     }
 }
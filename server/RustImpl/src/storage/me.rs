@@ -3,9 +3,9 @@ use super::super::action::{ActionFuture, Call, ListME, XferME};
 use super::super::factory::Factory;
 use super::super::item::{Item, ItemStack};
 use super::super::util::{alive, spawn};
-use super::{DepositResult, Extractor, IntoStorage, Provider, Storage};
+use super::{DepositResult, DepositRule, Extractor, IntoStorage, Provider, Storage};
 use abort_on_drop::ChildTask;
-use flexstr::{local_str, LocalStr};
+use flexstr::{local_fmt, local_str, LocalStr};
 use fnv::FnvHashMap;
 use std::{
     cell::RefCell,
@@ -14,6 +14,8 @@ use std::{
 
 pub struct MEConfig {
     pub accesses: Vec<MEAccess>,
+    pub deposit: DepositRule,
+    pub extract_priority: i32,
 }
 
 pub struct MEStorage {
@@ -56,7 +58,8 @@ impl Storage for MEStorage {
             for mut stack in stacks.into_iter() {
                 Rc::get_mut(&mut stack.item).unwrap().others.remove(&"isCraftable".into());
                 factory.register_stored_item(stack.item.clone()).provide(Provider {
-                    priority: i32::MAX,
+                    storage_priority: this.config.extract_priority,
+                    priority: i64::MAX,
                     n_provided: stack.size.into(),
                     extractor: Rc::new(MEExtractor { weak: weak.clone(), item: stack.item }),
                 })
@@ -66,8 +69,11 @@ impl Storage for MEStorage {
     }
 
     fn cleanup(&mut self) { self.access_for_item.clear() }
+    fn describe(&self) -> LocalStr { local_fmt!("ME {}", self.config.accesses[0].me_addr) }
 
-    fn deposit_priority(&mut self, _item: &Rc<Item>) -> Option<i32> { Some(i32::MIN) }
+    fn get_deposit_rule(&self) -> &DepositRule { &self.config.deposit }
+
+    fn deposit_priority(&mut self, _item: &Rc<Item>) -> Option<i64> { Some(i64::MIN) }
 
     fn deposit(&mut self, factory: &Factory, stack: &ItemStack, bus_slot: usize) -> DepositResult {
         let n_deposited = stack.size;
@@ -91,7 +97,7 @@ impl Storage for MEStorage {
 }
 
 impl Extractor for MEExtractor {
-    fn extract(&self, factory: &Factory, size: i32, bus_slot: usize) -> ChildTask<Result<(), LocalStr>> {
+    fn extract(&self, factory: &Factory, size: i64, bus_slot: usize) -> ChildTask<Result<(), LocalStr>> {
         upgrade_mut!(self.weak, this);
         let server = factory.borrow_server();
         let accesses = &this.config.accesses;
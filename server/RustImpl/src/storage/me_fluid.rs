@@ -0,0 +1,130 @@
+use super::super::access::MEFluidAccess;
+use super::super::action::{ActionFuture, Call, ListMEFluid, XferMEFluid};
+use super::super::factory::Factory;
+use super::super::lua_value::Table;
+use super::super::server::Server;
+use super::super::util::{alive, spawn};
+use super::FluidExtractor;
+use abort_on_drop::ChildTask;
+use flexstr::{local_fmt, local_str, LocalStr};
+use fnv::FnvHashMap;
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+pub struct MEFluidConfig {
+    pub accesses: Vec<MEFluidAccess>,
+}
+
+pub struct MEFluidStorage {
+    weak: Weak<RefCell<MEFluidStorage>>,
+    config: MEFluidConfig,
+    factory: Weak<RefCell<Factory>>,
+    // By name: (label, unreserved amount).
+    fluids: FnvHashMap<LocalStr, (LocalStr, i64)>,
+}
+
+struct MEFluidExtractor {
+    weak: Weak<RefCell<MEFluidStorage>>,
+    fluid: LocalStr,
+}
+
+impl MEFluidStorage {
+    pub fn new(config: MEFluidConfig, factory: &Factory) -> Rc<RefCell<Self>> {
+        Rc::new_cyclic(|weak| {
+            RefCell::new(Self {
+                weak: weak.clone(),
+                config,
+                factory: factory.weak.clone(),
+                fluids: FnvHashMap::default(),
+            })
+        })
+    }
+
+    pub fn update(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+        let server = factory.borrow_server();
+        let access = server.load_balance(&self.config.accesses).1;
+        let action = ActionFuture::from(ListMEFluid { addr: access.me_addr.clone() });
+        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        let weak = self.weak.clone();
+        spawn(async move {
+            let fluids = action.await?;
+            alive_mut!(weak, this);
+            for fluid in fluids {
+                this.fluids.insert(fluid.name, (fluid.label, fluid.qty));
+            }
+            Ok(())
+        })
+    }
+
+    pub fn cleanup(&mut self) { self.fluids.clear() }
+    pub fn get_n_stored(&self, fluid: &str) -> i64 { self.fluids.get(fluid).map_or(0, |(_, qty)| *qty) }
+
+    // Reserves up to qty, returning the amount reserved.
+    pub fn reserve(&mut self, fluid: &str, qty: i64) -> (Rc<dyn FluidExtractor>, i64) {
+        let n_reserved = self.fluids.get_mut(fluid).map_or(0, |(_, n_stored)| {
+            let n_reserved = qty.min(*n_stored);
+            *n_stored -= n_reserved;
+            n_reserved
+        });
+        (Rc::new(MEFluidExtractor { weak: self.weak.clone(), fluid: LocalStr::from_ref(fluid) }), n_reserved)
+    }
+
+    pub fn get_accesses(&self) -> &[MEFluidAccess] { &self.config.accesses }
+
+    // The access is picked by the caller to balance the load across all ME fluid storages.
+    pub fn deposit(
+        server: &Server,
+        access: &MEFluidAccess,
+        bus: usize,
+        slot: usize,
+        fluid: LocalStr,
+        qty: i64,
+    ) -> ChildTask<Result<(), LocalStr>> {
+        let bus_of_tank = &access.buses[bus];
+        let action = ActionFuture::from(Call {
+            addr: bus_of_tank.addr.clone(),
+            func: local_str!("transferFluid"),
+            args: vec![bus_of_tank.bus_side.into(), bus_of_tank.tank_side.into(), qty.into(), (slot + 1).into()],
+        });
+        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        spawn(async move {
+            // transferFluid returns whether it succeeded and the amount moved.
+            let n_moved = Table::try_from(action.await?)?.remove(&2.into()).map_or(Ok(0), i64::try_from)?;
+            if n_moved < qty {
+                Err(local_fmt!("{fluid} is full"))
+            } else {
+                Ok(())
+            }
+        })
+    }
+}
+
+impl FluidExtractor for MEFluidExtractor {
+    fn extract(&self, qty: i64, bus: usize) -> ChildTask<Result<(), LocalStr>> {
+        upgrade!(self.weak, this);
+        upgrade!(this.factory, factory);
+        let server = factory.borrow_server();
+        let access = server.load_balance(&this.config.accesses).1;
+        let bus_of_tank = &access.buses[bus];
+        // Fluids go through the database as fluid drops, which are labeled after the fluid.
+        let label = this.fluids.get(&self.fluid).map_or(&self.fluid, |(label, _)| label);
+        let mut filter = Table::new();
+        filter.insert("label".into(), label.clone().into());
+        let action = ActionFuture::from(XferMEFluid {
+            me_addr: access.me_addr.clone(),
+            me_slot: access.me_slot,
+            filter: filter.into(),
+            transposer_addr: bus_of_tank.addr.clone(),
+            transposer_args: vec![
+                bus_of_tank.tank_side.into(),
+                bus_of_tank.bus_side.into(),
+                qty.into(),
+                (access.me_slot + 1).into(),
+            ],
+        });
+        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        spawn(action)
+    }
+}
@@ -1,5 +1,5 @@
 use super::factory::Factory;
-use super::item::{Item, ItemStack};
+use super::item::{Filter, Item, ItemStack};
 use abort_on_drop::ChildTask;
 use flexstr::LocalStr;
 use std::{
@@ -9,14 +9,60 @@ use std::{
 };
 
 pub struct DepositResult {
-    pub n_deposited: i32,
+    pub n_deposited: i64,
     pub task: ChildTask<Result<(), LocalStr>>,
 }
 
+// Which items a storage takes and how it ranks against other storages.
+pub struct DepositRule {
+    pub priority: i32,
+    // If non-empty, only matching items are deposited.
+    pub accept: Vec<Filter>,
+    pub deny: Vec<Filter>,
+}
+
+impl DepositRule {
+    pub fn new(priority: i32) -> Self { DepositRule { priority, accept: Vec::new(), deny: Vec::new() } }
+
+    pub fn accept(mut self, filter: Filter) -> Self {
+        self.accept.push(filter);
+        self
+    }
+
+    pub fn deny(mut self, filter: Filter) -> Self {
+        self.deny.push(filter);
+        self
+    }
+
+    pub fn accepts(&self, item: &Item) -> bool {
+        (self.accept.is_empty() || self.accept.iter().any(|x| x.apply(item)))
+            && !self.deny.iter().any(|x| x.apply(item))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct StorageUsage {
+    pub n_used: usize,
+    pub n_total: usize,
+}
+
+pub struct PartialStack {
+    pub item: Rc<Item>,
+    pub size: i64,
+    pub extractor: Rc<dyn Extractor>,
+}
+
 pub trait Storage: 'static {
     fn update(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>>;
     fn cleanup(&mut self);
-    fn deposit_priority(&mut self, item: &Rc<Item>) -> Option<i32>;
+    fn describe(&self) -> LocalStr;
+    // Slots used as of the last update, if the storage can tell.
+    fn get_usage(&self) -> Option<StorageUsage> { None }
+    // Stacks that could be merged with others of the same item to free up slots.
+    fn get_partial_stacks(&self) -> Vec<PartialStack> { Vec::new() }
+    fn get_deposit_rule(&self) -> &DepositRule;
+    // Ranks the storage among those with the same rule priority.
+    fn deposit_priority(&mut self, item: &Rc<Item>) -> Option<i64>;
     fn deposit(&mut self, factory: &Factory, stack: &ItemStack, bus_slot: usize) -> DepositResult;
 }
 
@@ -26,17 +72,31 @@ pub trait IntoStorage {
 }
 
 pub trait Extractor {
-    fn extract(&self, factory: &Factory, size: i32, bus_slot: usize) -> ChildTask<Result<(), LocalStr>>;
+    fn extract(&self, factory: &Factory, size: i64, bus_slot: usize) -> ChildTask<Result<(), LocalStr>>;
+}
+
+pub trait FluidExtractor {
+    fn extract(&self, qty: i64, bus: usize) -> ChildTask<Result<(), LocalStr>>;
+}
+
+// How a chest orders its own stacks of the same item for extraction.
+#[derive(Clone, Copy)]
+pub enum ExtractStrategy {
+    // Frees up slots faster.
+    SmallestFirst,
+    LargestFirst,
 }
 
+// Providers are drained in order of the storage's `extract_priority` first, then `priority`.
 pub struct Provider {
-    priority: i32,
-    pub n_provided: Cell<i32>,
+    storage_priority: i32,
+    priority: i64,
+    pub n_provided: Cell<i64>,
     pub extractor: Rc<dyn Extractor>,
 }
 
 impl PartialEq<Provider> for Provider {
-    fn eq(&self, other: &Self) -> bool { self.priority == other.priority }
+    fn eq(&self, other: &Self) -> bool { self.cmp(other) == Ordering::Equal }
 }
 
 impl Eq for Provider {}
@@ -46,12 +106,18 @@ impl PartialOrd<Provider> for Provider {
 }
 
 impl Ord for Provider {
-    fn cmp(&self, other: &Self) -> Ordering { self.priority.cmp(&other.priority) }
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.storage_priority, self.priority).cmp(&(other.storage_priority, other.priority))
+    }
 }
 
 mod chest;
 mod drawer;
 mod me;
+mod me_fluid;
+mod rs;
 pub use chest::*;
 pub use drawer::*;
 pub use me::*;
+pub use me_fluid::*;
+pub use rs::*;
@@ -0,0 +1,116 @@
+use super::super::access::RSAccess;
+use super::super::action::{ActionFuture, Call, ListRS, XferRS};
+use super::super::factory::Factory;
+use super::super::item::{Item, ItemStack};
+use super::super::util::{alive, spawn};
+use super::{DepositResult, DepositRule, Extractor, IntoStorage, Provider, Storage};
+use abort_on_drop::ChildTask;
+use flexstr::{local_fmt, local_str, LocalStr};
+use fnv::FnvHashMap;
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+pub struct RSConfig {
+    pub accesses: Vec<RSAccess>,
+    pub deposit: DepositRule,
+    pub extract_priority: i32,
+}
+
+pub struct RSStorage {
+    weak: Weak<RefCell<RSStorage>>,
+    config: RSConfig,
+    factory: Weak<RefCell<Factory>>,
+    access_for_item: FnvHashMap<Rc<Item>, usize>,
+}
+
+struct RSExtractor {
+    weak: Weak<RefCell<RSStorage>>,
+    item: Rc<Item>,
+}
+
+impl IntoStorage for RSConfig {
+    type Output = RSStorage;
+    fn into_storage(self, factory: &Factory) -> Rc<RefCell<Self::Output>> {
+        Rc::new_cyclic(|weak| {
+            RefCell::new(Self::Output {
+                weak: weak.clone(),
+                config: self,
+                factory: factory.weak.clone(),
+                access_for_item: FnvHashMap::default(),
+            })
+        })
+    }
+}
+
+impl Storage for RSStorage {
+    fn update(&self, factory: &Factory) -> ChildTask<Result<(), LocalStr>> {
+        let server = factory.borrow_server();
+        let access = server.load_balance(&self.config.accesses).1;
+        let action = ActionFuture::from(ListRS { addr: access.rs_addr.clone() });
+        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        let weak = self.weak.clone();
+        spawn(async move {
+            let stacks = action.await?;
+            alive!(weak, this);
+            upgrade_mut!(this.factory, factory);
+            for stack in stacks.into_iter() {
+                factory.register_stored_item(stack.item.clone()).provide(Provider {
+                    storage_priority: this.config.extract_priority,
+                    priority: i64::MAX,
+                    n_provided: stack.size.into(),
+                    extractor: Rc::new(RSExtractor { weak: weak.clone(), item: stack.item }),
+                })
+            }
+            Ok(())
+        })
+    }
+
+    fn cleanup(&mut self) { self.access_for_item.clear() }
+    fn describe(&self) -> LocalStr { local_fmt!("RS {}", self.config.accesses[0].rs_addr) }
+
+    fn get_deposit_rule(&self) -> &DepositRule { &self.config.deposit }
+
+    fn deposit_priority(&mut self, _item: &Rc<Item>) -> Option<i64> { Some(i64::MIN) }
+
+    fn deposit(&mut self, factory: &Factory, stack: &ItemStack, bus_slot: usize) -> DepositResult {
+        let n_deposited = stack.size;
+        let server = factory.borrow_server();
+        let access = server.load_balance(&self.config.accesses).1;
+        let action = ActionFuture::from(Call {
+            addr: access.transposer_addr.clone(),
+            func: local_str!("transferItem"),
+            args: vec![access.bus_side.into(), access.rs_side.into(), n_deposited.into(), (bus_slot + 1).into()],
+        });
+        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        let task = spawn(async move { action.await.map(|_| ()) });
+        DepositResult { n_deposited, task }
+    }
+}
+
+impl Extractor for RSExtractor {
+    fn extract(&self, factory: &Factory, size: i64, bus_slot: usize) -> ChildTask<Result<(), LocalStr>> {
+        upgrade_mut!(self.weak, this);
+        let server = factory.borrow_server();
+        let accesses = &this.config.accesses;
+        let access = *this.access_for_item.entry(self.item.clone()).or_insert_with(|| server.load_balance(accesses).0);
+        let access = &this.config.accesses[access];
+        let action = ActionFuture::from(XferRS {
+            rs_addr: access.rs_addr.clone(),
+            filter: self.item.serialize(),
+            size,
+            direction: access.buffer_direction,
+            transposer_addr: access.transposer_addr.clone(),
+            transposer_args: vec![
+                access.buffer_side.into(),
+                access.bus_side.into(),
+                size.into(),
+                1.into(),
+                (bus_slot + 1).into(),
+            ],
+        });
+        server.enqueue_request_group(&access.client, vec![action.clone().into()]);
+        spawn(async move { action.await.map(|_| ()) })
+    }
+}